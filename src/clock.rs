@@ -0,0 +1,72 @@
+use std::{
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Time source for motion timing
+pub trait Clock: Send + Sync {
+    /// Seconds since the clock was created
+    fn now(&self) -> f64;
+    /// Called once per pass of the motion loop
+    fn tick(&self);
+    /// Let `seconds` pass without using the cpu
+    fn sleep(&self, seconds: &f64);
+}
+
+/// Wall clock time
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        SystemClock {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> f64 {
+        self.start.elapsed().as_secs_f64()
+    }
+    fn tick(&self) {}
+    fn sleep(&self, seconds: &f64) {
+        thread::sleep(Duration::from_secs_f64(seconds.max(0.0)));
+    }
+}
+
+/// Simulated time that advances a fixed amount on every tick
+pub struct VirtualClock {
+    now: Mutex<f64>,
+    resolution: f64,
+}
+
+impl VirtualClock {
+    /// resolution: seconds per tick
+    pub fn new(resolution: f64) -> Self {
+        VirtualClock {
+            now: Mutex::new(0.0),
+            resolution,
+        }
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> f64 {
+        *self.now.lock().unwrap()
+    }
+    fn tick(&self) {
+        *self.now.lock().unwrap() += self.resolution;
+    }
+    fn sleep(&self, seconds: &f64) {
+        *self.now.lock().unwrap() += seconds.max(0.0);
+    }
+}
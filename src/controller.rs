@@ -1,27 +1,116 @@
-use std::{io, path::PathBuf};
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+};
 
+use clap::ValueEnum;
 use log::{error, info};
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    draw::{heart_wave, spiralgraph, square, star, wave},
-    gcode::{Axis, AxisLimit, PlotterInstruction, PlotterProgram},
-    motor::{Motor, Side, StepInstruction},
+    clock::{Clock, SystemClock},
+    draw::Pattern,
+    gcode::{Axis, AxisLimit, Feed, PaperLimits, PlotterInstruction, PlotterProgram, ReadOptions},
+    motor::{DriverKind, Motor, Side, SimulatedHardware, StepInstruction},
+    pen::PenLift,
     physical::Physical,
+    planner::Planner,
     position::{Position, PositionMM, PositionStep},
     predictor::{Prediction, Predictor},
+    preview::{preview, DEFAULT_PX_PER_MM},
+    queue::{Job, JobQueue, JobSource},
     scurve::{SCurve, SCurveSolver},
+    state::SessionState,
 };
 
+/// How far in mm past where an endstop should close homing carries on reeling in, and how
+/// far it pays out a cord whose endstop is already closed
+const HOMING_MARGIN_MM: f64 = 20.0;
+
 enum ControllerMode {
     Ask,
     MoveTo,
+    Home,
     QueryPaper,
     QueryPosition,
     InitProgram,
+    ResumeProgram,
     RunProgram,
     LoadPattern,
     ScaleProgram,
     CenterProgram,
+    EditQueue,
+    NextJob,
+    ExportProgram,
+    PreviewProgram,
+    Quit,
+}
+
+/// How to fit a program to the paper before running it
+#[derive(Clone, Copy, Debug, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Fit {
+    /// Run the program where it is
+    None,
+    /// Move the program to the middle of the paper
+    Center,
+    /// Scale the program up or down to fill the paper, keeping its aspect ratio
+    Scale,
+}
+
+impl Fit {
+    /// Move or scale a program onto the paper
+    pub fn apply(
+        &self,
+        program: &mut PlotterProgram,
+        x_limits: &AxisLimit,
+        y_limits: &AxisLimit,
+    ) -> Result<(), &'static str> {
+        match self {
+            Fit::None => Ok(()),
+            Fit::Center => program.center_keep_aspect(x_limits, y_limits),
+            Fit::Scale => program.scale_keep_aspect(x_limits, y_limits),
+        }
+    }
+}
+
+/// What the controller is doing, for displays outside the process
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Status {
+    running: bool,
+    paused: bool,
+    /// Index of the next program instruction
+    instruction: usize,
+    len: usize,
+    time_remaining: f64,
+    time_remaining_next_lift: Option<f64>,
+    bad_steps_prevented: u64,
+    /// Pen position in mm, if known
+    position: Option<[f64; 2]>,
+}
+
+impl Status {
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+    pub fn get_position(&self) -> &Option<[f64; 2]> {
+        &self.position
+    }
+}
+
+/// Requests an operator can send to a running program from another thread
+#[derive(Clone, Copy, Debug)]
+pub enum ControlCommand {
+    /// Slow to a stop and wait for `Resume` or `Abort`
+    Pause { lift_pen: bool },
+    /// Carry on with the paused instruction
+    Resume,
+    /// Stop and go back to the menu, keeping the position
+    Abort,
 }
 
 #[derive(PartialEq, Eq, PartialOrd, Ord)]
@@ -30,7 +119,7 @@ pub enum MoveStatus {
     Moving,
 }
 
-fn format_time(secs: f64) -> String {
+pub fn format_time(secs: f64) -> String {
     if secs < 60.0 {
         format!("{secs:02.1}")
     } else if secs < 60.0 * 60.0 {
@@ -45,63 +134,246 @@ fn format_time(secs: f64) -> String {
     }
 }
 pub struct Controller {
+    clock: Arc<dyn Clock>,
     current_position: Position,
     current_position_initialized: bool,
     motors: [Motor; 2],
+    pen_lift: Box<dyn PenLift>,
     mode: ControllerMode,
     paper_limits: Option<[AxisLimit; 2]>,
     physical: Physical,
     move_status: MoveStatus,
     s_curve: SCurve,
     solver: SCurveSolver,
+    planner: Planner,
     predictor: Predictor,
     wait_count: usize,
     program: Option<PlotterProgram>,
     bad_steps_prevented: u64,
+    pen_down: bool,
+    control: Receiver<ControlCommand>,
+    control_sender: Sender<ControlCommand>,
+    /// Where to keep the session across restarts
+    state_path: Option<PathBuf>,
+    /// Number of program instructions that finished
+    completed: usize,
+    paused: bool,
+    /// Wait for a paused program to be resumed on standard in as well as the control channel
+    prompt: bool,
+    status: Arc<Mutex<Status>>,
+    /// Waiting for input with the motors still, see `idle_while`
+    idle: Arc<Mutex<bool>>,
+    /// Used to read files picked from the menu, the job queue or the server
+    read_options: ReadOptions,
+    queue: JobQueue,
+    /// Where to keep the job queue, once one has been loaded
+    queue_path: Option<PathBuf>,
+    /// A job from the queue is running, or the queue is waiting between jobs
+    queue_running: bool,
 }
 
 impl Controller {
-    pub fn new(gcode_path: Option<PathBuf>) -> Controller {
-        let physical = Physical::new();
-        let motors = [
-            Motor::new(Side::Left, *physical.get_min_seconds_per_step()),
-            Motor::new(Side::Right, *physical.get_min_seconds_per_step()),
-        ];
+    pub fn new(
+        physical: Physical,
+        gcode_path: Option<PathBuf>,
+        read_options: ReadOptions,
+        driver: DriverKind,
+        state_path: PathBuf,
+    ) -> Controller {
+        // a recording driver's pen starts at home
+        let hardware = SimulatedHardware::new(physical.get_home(), &physical);
+        let motors = Controller::make_motors(&physical, driver, &hardware);
+        let gcode_program = Controller::load_gcode(&gcode_path, &physical, &read_options);
+        let pen_lift = driver.build_pen_lift(&physical);
+        let mut controller = Controller::with_hardware(
+            physical,
+            motors,
+            pen_lift,
+            Arc::new(SystemClock::new()),
+            gcode_program,
+        );
+        controller.state_path = Some(state_path);
+        controller.read_options = read_options;
+        controller
+    }
+
+    /// Build a controller around already constructed motors and clock
+    pub fn with_hardware(
+        physical: Physical,
+        motors: [Motor; 2],
+        pen_lift: Box<dyn PenLift>,
+        clock: Arc<dyn Clock>,
+        program: Option<PlotterProgram>,
+    ) -> Controller {
         info!("Physical: {physical}");
-        let max_acceleration = 1e4;
-        let max_jerk = 1e9;
-        let solver = SCurveSolver::new(&physical, max_acceleration, max_jerk);
+        let solver = SCurveSolver::new(
+            &physical,
+            *physical.get_max_acceleration(),
+            *physical.get_max_jerk(),
+        );
         info!("solver: {solver}");
-        let gcode_program = Controller::load_gcode(&gcode_path, physical.get_max_velocity());
+        let planner = Planner::new(
+            *physical.get_look_ahead(),
+            *physical.get_junction_deviation(),
+        );
+        let (control_sender, control) = mpsc::channel();
         Controller {
+            clock,
             current_position: Position::default(),
             current_position_initialized: false,
             motors,
+            pen_lift,
             mode: ControllerMode::QueryPosition,
             paper_limits: None,
             solver,
+            planner,
             physical,
             move_status: MoveStatus::Stopped,
             s_curve: SCurve::default(),
             predictor: Predictor::default(),
             wait_count: 0,
-            program: gcode_program,
+            program,
             bad_steps_prevented: 0,
+            pen_down: false,
+            control,
+            control_sender,
+            state_path: None,
+            completed: 0,
+            paused: false,
+            prompt: true,
+            status: Arc::new(Mutex::new(Status::default())),
+            idle: Arc::new(Mutex::new(false)),
+            read_options: ReadOptions::default(),
+            queue: JobQueue::default(),
+            queue_path: None,
+            queue_running: false,
+        }
+    }
+
+    pub fn make_motors(
+        physical: &Physical,
+        driver: DriverKind,
+        hardware: &SimulatedHardware,
+    ) -> [Motor; 2] {
+        let make_motor = |side| {
+            Motor::new(
+                side,
+                *physical.get_min_seconds_per_step(),
+                driver.build(side, hardware),
+                driver.build_endstop(side, hardware),
+            )
+        };
+        [make_motor(Side::Left), make_motor(Side::Right)]
+    }
+
+    /// Ask whether to pick up the session saved in the state file
+    pub fn offer_restore(&mut self) {
+        let Some(path) = &self.state_path else {
+            return;
+        };
+        let state = match SessionState::load(path) {
+            Ok(Some(state)) => state,
+            Ok(None) => return,
+            Err(msg) => {
+                error!("{msg}");
+                return;
+            }
+        };
+        println!("Restore saved session? {state} (y/n)");
+        if !matches!(self.get_char_from_user(), Ok('y')) {
+            return;
+        }
+        if let Err(msg) = self.restore(&state) {
+            error!("{msg}");
+        }
+    }
+
+    fn restore(&mut self, state: &SessionState) -> Result<(), &'static str> {
+        if let Some([x_limit, y_limit]) = state.get_paper_limits() {
+            self.paper_limits = Some([AxisLimit::new(*x_limit), AxisLimit::new(*y_limit)]);
+        }
+        if let Some(path) = state.get_program_path() {
+            let mut program =
+                Controller::load_gcode(&Some(path.clone()), &self.physical, &self.read_options)
+                    .ok_or("Failed to reload program")?;
+            for transform in state.get_transforms() {
+                if let Err(msg) = program.apply(transform) {
+                    error!("{msg}");
+                }
+            }
+            info!(
+                "program had finished {}/{} instructions, r(E)sume to carry on",
+                state.get_completed(),
+                program.len()
+            );
+            self.program = Some(program);
+            self.completed = *state.get_completed();
+        }
+        if let Some(rr) = state.get_step() {
+            self.set_current_step(PositionStep::new(*rr))?;
+            self.mode = ControllerMode::Ask;
+        }
+        Ok(())
+    }
+
+    fn session_state(&self) -> SessionState {
+        let step = self.current_position_initialized.then(|| {
+            let step = self.current_position.get_step();
+            [step[0], step[1]]
+        });
+        let paper_limits = self
+            .paper_limits
+            .as_ref()
+            .map(|[x_limit, y_limit]| [*x_limit.get(), *y_limit.get()]);
+        let (program_path, transforms) = match &self.program {
+            Some(program) => (
+                program.get_source().clone(),
+                program.get_transforms().to_vec(),
+            ),
+            None => (None, Vec::new()),
+        };
+        SessionState::new(step, paper_limits, program_path, transforms, self.completed)
+    }
+
+    /// Write the session to the state file, if there is one
+    pub fn save_state(&self) {
+        let Some(path) = &self.state_path else {
+            return;
+        };
+        if let Err(msg) = self.session_state().save(path) {
+            error!("{msg}");
         }
     }
 
     // TODO: implement better timing info
 
-    fn load_gcode(gcode_path: &Option<PathBuf>, max_velocity: &f64) -> Option<PlotterProgram> {
+    /// Replace the loaded program with a gcode, svg, hpgl or dxf file
+    pub fn load_program(&mut self, path: &Path) -> Result<(), &'static str> {
+        let program =
+            PlotterProgram::read_file(path, &self.physical, &self.read_options).map_err(|e| {
+                error!("{e}");
+                "Invalid program"
+            })?;
+        info!("read: {}", path.display());
+        self.program = Some(program);
+        self.completed = 0;
+        Ok(())
+    }
+
+    fn load_gcode(
+        gcode_path: &Option<PathBuf>,
+        physical: &Physical,
+        read_options: &ReadOptions,
+    ) -> Option<PlotterProgram> {
         if gcode_path.is_none() {
             return None;
         }
         let gcode_file =
-            PlotterProgram::read_gcode_file(gcode_path.as_ref().unwrap(), max_velocity);
+            PlotterProgram::read_file(gcode_path.as_ref().unwrap(), physical, read_options);
         match gcode_file {
             Err(msg) => {
                 error!("{msg}");
-                error!("Invalid gcode program");
+                error!("Invalid program");
                 None
             }
             Ok(gcode_file) => {
@@ -112,9 +384,9 @@ impl Controller {
         }
     }
 
-    fn get_scalar_from_user() -> Result<f64, &'static str> {
+    fn get_scalar_from_user(&self) -> Result<f64, &'static str> {
         let mut input = String::new();
-        if let Err(error) = io::stdin().read_line(&mut input) {
+        if let Err(error) = self.idle_while(|| io::stdin().read_line(&mut input)) {
             log::error!("error: {error}");
             return Err("Failed to read from standard in");
         }
@@ -126,36 +398,35 @@ impl Controller {
         }
         Ok(side.unwrap())
     }
-    fn get_position_from_user() -> Result<PositionMM, &'static str> {
+    fn get_position_from_user(&self) -> Result<PositionMM, &'static str> {
         let mut input = String::new();
-        if let Err(error) = io::stdin().read_line(&mut input) {
+        if let Err(error) = self.idle_while(|| io::stdin().read_line(&mut input)) {
             error!("{error}");
             return Err("stdin: read_line failed");
         }
-        let xy = {
-            let input = input.trim();
-            let Some(xy_s) = input.split_once(',') else {
-                return Err("Did not get expected format");
-            };
-            let xy_s = [xy_s.0, xy_s.1];
-            info!("got {}, {}", xy_s[0], xy_s[1]);
-            let mut xy_f: [f64; 2] = [0.0, 0.0];
-            for (s, f) in xy_s.iter().zip(xy_f.iter_mut()) {
-                if let Ok(pf) = s.parse::<f64>() {
-                    *f = pf;
-                } else {
-                    error!("Failed to parse \"{}\"", s);
-                    return Err("Failed to parse");
-                }
-            }
-            xy_f
-        };
-        Ok(PositionMM::new(xy))
+        let mm = input.parse::<PositionMM>()?;
+        info!("got {mm}");
+        Ok(mm)
+    }
+
+    fn get_line_from_user(&self) -> Result<String, &'static str> {
+        let mut input = String::new();
+        if let Err(error) = self.idle_while(|| io::stdin().read_line(&mut input)) {
+            error!("{error}");
+            return Err("Failed to read from standard in");
+        }
+        Ok(input.trim().to_string())
     }
 
-    fn get_char_from_user() -> Result<char, &'static str> {
+    fn get_index_from_user(&self) -> Result<usize, &'static str> {
+        self.get_line_from_user()?
+            .parse()
+            .map_err(|_| "Could not parse")
+    }
+
+    fn get_char_from_user(&self) -> Result<char, &'static str> {
         let mut input = String::new();
-        if let Err(error) = io::stdin().read_line(&mut input) {
+        if let Err(error) = self.idle_while(|| io::stdin().read_line(&mut input)) {
             log::error!("error: {error}");
             return Err("read line error");
         }
@@ -173,19 +444,25 @@ impl Controller {
     }
 
     fn set_mode_from_user(&mut self) {
-        println!("What should we do? (M)ove, (C)enter program, sc(A)le program, (R)un gcode, l(O)ad pattern, set paper (L)imits, or set (P)osition");
-        let first_char = Controller::get_char_from_user();
+        println!("What should we do? (M)ove, (H)ome, (C)enter program, sc(A)le program, (R)un gcode, r(E)sume gcode, l(O)ad pattern, pre(V)iew, e(X)port gcode, (J)ob queue, set paper (L)imits, set (P)osition, or (Q)uit");
+        let first_char = self.get_char_from_user();
         if first_char.is_err() {
             return;
         }
         self.mode = match first_char.unwrap() {
             'm' => ControllerMode::MoveTo,
+            'h' => ControllerMode::Home,
             'o' => ControllerMode::LoadPattern,
+            'j' => ControllerMode::EditQueue,
+            'x' => ControllerMode::ExportProgram,
+            'v' => ControllerMode::PreviewProgram,
             'p' => ControllerMode::QueryPosition,
             'r' => ControllerMode::InitProgram,
+            'e' => ControllerMode::ResumeProgram,
             'c' => ControllerMode::CenterProgram,
             'a' => ControllerMode::ScaleProgram,
             'l' => ControllerMode::QueryPaper,
+            'q' => ControllerMode::Quit,
             _ => {
                 println!("Unknown mode.");
                 ControllerMode::Ask
@@ -194,50 +471,249 @@ impl Controller {
     }
     fn set_current_position_from_user(&mut self) -> Result<(), &'static str> {
         println!("What's the current position in mm? provide \"x,y\"");
-        let mm = Controller::get_position_from_user()?;
+        let mm = self.get_position_from_user()?;
+        self.set_current_position(mm)
+    }
+    pub fn bad_steps_prevented(&self) -> &u64 {
+        &self.bad_steps_prevented
+    }
+    /// Channel to pause, resume or abort a running program through
+    pub fn control(&self) -> Sender<ControlCommand> {
+        self.control_sender.clone()
+    }
+    /// Only take resume and abort from the control channel, for when nobody is at the terminal
+    pub fn disable_prompts(&mut self) {
+        self.prompt = false;
+    }
+    /// Status that `publish_status` keeps up to date
+    pub fn shared_status(&self) -> Arc<Mutex<Status>> {
+        self.status.clone()
+    }
+    /// Flag that is set while the controller waits for input with nothing moving
+    ///
+    /// Holding its lock keeps the controller from leaving the wait, so the process can end
+    /// without stopping a move part way.
+    pub fn shared_idle(&self) -> Arc<Mutex<bool>> {
+        self.idle.clone()
+    }
+    /// Run `wait`, which must not move anything, with the controller marked idle
+    pub fn idle_while<T>(&self, wait: impl FnOnce() -> T) -> T {
+        *self.idle.lock().unwrap() = true;
+        let result = wait();
+        *self.idle.lock().unwrap() = false;
+        result
+    }
+    pub fn publish_status(&self) {
+        let mut status = Status {
+            running: self.is_running_program(),
+            paused: self.paused,
+            bad_steps_prevented: self.bad_steps_prevented,
+            position: self.get_current_position().map(|mm| [*mm.x(), *mm.y()]),
+            ..Status::default()
+        };
+        if let Some(program) = &self.program {
+            status.instruction = program.current_position();
+            status.len = program.len();
+            status.time_remaining = *program.time_remaining();
+            status.time_remaining_next_lift = program.time_remaining_next_lift();
+        }
+        *self.status.lock().unwrap() = status;
+    }
+    /// The operator asked to quit from the menu
+    pub fn quit_requested(&self) -> bool {
+        matches!(self.mode, ControllerMode::Quit)
+    }
+    /// Number of upcoming moves to pass to `run_instruction`
+    pub fn look_ahead(&self) -> &usize {
+        self.planner.get_look_ahead()
+    }
+    pub fn position_known(&self) -> bool {
+        self.current_position_initialized
+    }
+    /// Pen position in mm, if known
+    pub fn get_current_position(&self) -> Option<PositionMM> {
+        self.current_position_initialized
+            .then(|| self.current_position.into())
+    }
+    pub fn get_physical(&self) -> &Physical {
+        &self.physical
+    }
+    pub fn set_current_position(&mut self, mm: PositionMM) -> Result<(), &'static str> {
+        self.physical.check_position(&mm)?;
         self.current_position = Position::from_mm(mm, &self.physical);
         self.current_position_initialized = true;
         info!("position set to {}", self.current_position);
         Ok(())
     }
+    fn set_current_step(&mut self, step: PositionStep) -> Result<(), &'static str> {
+        self.physical.check_step(&step)?;
+        self.current_position = Position::from_step(step, &self.physical);
+        self.current_position_initialized = true;
+        info!("position set to {}", self.current_position);
+        Ok(())
+    }
     fn set_paper_limits_from_user(&mut self) -> Result<(), &'static str> {
         println!("Paper X min,max?");
-        let x_limit = Controller::get_position_from_user()?.into();
+        let x_limit = self.get_position_from_user()?.into();
         println!("Paper Y min,max?");
-        let mut y_limit = Controller::get_position_from_user()?.into();
+        let y_limit = self.get_position_from_user()?.into();
+        self.set_paper_limits(x_limit, y_limit);
+        Ok(())
+    }
+    /// y_limit: before the machine's y offset is added
+    pub fn set_paper_limits(&mut self, x_limit: AxisLimit, mut y_limit: AxisLimit) {
         self.physical.adjust_paper_y_limit(&mut y_limit);
         self.paper_limits = Some([x_limit, y_limit]);
-        Ok(())
     }
     fn get_jog_from_user(&mut self) -> Result<PositionMM, ()> {
         println!("Where to? provide \"x,y\"");
         for _ in 0..1 {
-            if let Ok(mm) = Controller::get_position_from_user() {
+            if let Ok(mm) = self.get_position_from_user() {
                 return Ok(mm);
             }
         }
         Err(())
     }
     /// Initialize move to new location. Set up s-curve and change status.
-    fn init_move(&mut self, mm: &PositionMM) {
+    ///
+    /// velocity: coast velocity in mm/s
+    /// upcoming: targets and coast velocities of the moves that follow
+    fn init_move(&mut self, mm: &PositionMM, velocity: &f64, upcoming: &[(PositionMM, f64)]) {
         if self.current_position.very_close_to(mm, &self.physical) {
             self.move_status = MoveStatus::Stopped;
             return;
         }
+        let start: PositionMM = self.current_position.into();
+        let entry = *self.s_curve.get_exit_velocity();
+        let mut path = vec![(*mm, *velocity)];
+        path.extend_from_slice(upcoming);
+        let exit = self
+            .planner
+            .exit_velocity(&self.solver, &start, &entry, &path);
+        // carry on from where the last move left off if it did not stop
+        let t_start = if entry > 0.0 {
+            self.s_curve.end_time()
+        } else {
+            self.clock.now()
+        };
         // init s-curve
-        self.s_curve = self.solver.solve_curve(self.current_position.into(), *mm);
-        self.predictor = Predictor::new();
+        self.s_curve = self
+            .solver
+            .solve_curve(start, *mm, &entry, &exit, velocity, t_start);
+        self.predictor = Predictor::new(self.clock.as_ref());
         self.move_status = MoveStatus::Moving;
         self.wait_count = 0;
     }
+    /// Give up on the current move where it is, the next move starts from rest
+    fn abort_move(&mut self) {
+        self.move_status = MoveStatus::Stopped;
+        self.s_curve = SCurve::default();
+    }
+    /// Slow to a stop along the current move, the next move starts from rest
+    fn stop_move(&mut self) -> Result<(), &'static str> {
+        let velocity = self.s_curve.get_velocity(self.clock.as_ref());
+        if self.move_status == MoveStatus::Moving && velocity > 0.0 {
+            self.s_curve = self.solver.solve_stop(
+                self.s_curve.get_position(self.clock.as_ref()),
+                self.s_curve.get_direction(),
+                &velocity,
+                self.clock.now(),
+            );
+            while self.move_status == MoveStatus::Moving {
+                self.update_move()?;
+            }
+        }
+        self.abort_move();
+        Ok(())
+    }
+    /// Wait for the operator to resume or abort, from the control channel or standard in
+    fn wait_for_resume(&mut self) -> ControlCommand {
+        if !self.prompt {
+            loop {
+                match self.control.recv() {
+                    Ok(ControlCommand::Pause { .. }) => continue,
+                    Ok(command) => return command,
+                    Err(_) => return ControlCommand::Abort,
+                }
+            }
+        }
+        loop {
+            if let Ok(command) = self.control.try_recv() {
+                if !matches!(command, ControlCommand::Pause { .. }) {
+                    return command;
+                }
+                continue;
+            }
+            println!("Paused. (R)esume or (A)bort?");
+            match self.get_char_from_user() {
+                Ok('r') => return ControlCommand::Resume,
+                Ok('a') => return ControlCommand::Abort,
+                _ => println!("Unknown option."),
+            }
+        }
+    }
+    /// Drop commands sent while nothing was running
+    pub fn drain_control(&self) {
+        while self.control.try_recv().is_ok() {}
+    }
+    /// Act on a control command received in the middle of a move
+    ///
+    /// Returns once the move may carry on, with whether it was stopped and has to be planned
+    /// again, or with an error if the program was aborted. A resume with nothing paused is
+    /// dropped.
+    fn interrupt(&mut self, command: ControlCommand) -> Result<bool, &'static str> {
+        let lift_pen = match command {
+            ControlCommand::Pause { lift_pen } => lift_pen,
+            ControlCommand::Abort => false,
+            ControlCommand::Resume => {
+                info!("ignoring resume, nothing is paused");
+                return Ok(false);
+            }
+        };
+        self.stop_move()?;
+        if let ControlCommand::Abort = command {
+            info!("aborted at {}", self.current_position);
+            return Err("Program aborted");
+        }
+        self.save_state();
+        let lifted = lift_pen && self.pen_down;
+        if lifted {
+            self.pen_lift.up(self.clock.as_ref());
+        }
+        info!("paused at {}", self.current_position);
+        self.paused = true;
+        self.publish_status();
+        let command = self.wait_for_resume();
+        self.drain_control();
+        self.paused = false;
+        self.publish_status();
+        if let ControlCommand::Abort = command {
+            if lifted {
+                self.pen_down = false;
+            }
+            info!("aborted at {}", self.current_position);
+            return Err("Program aborted");
+        }
+        if lifted {
+            self.pen_lift.down(self.clock.as_ref());
+        }
+        info!("resumed");
+        Ok(true)
+    }
     /// Move current position in steps to (x, y)
-    fn update_move(&mut self) {
-        self.move_status = self.s_curve.get_move_status();
+    fn update_move(&mut self) -> Result<(), &'static str> {
+        self.clock.tick();
+        self.move_status = self.s_curve.get_move_status(self.clock.as_ref());
         if self.move_status == MoveStatus::Stopped {
-            return;
+            return Ok(());
         }
-        let desired = self.s_curve.get_desired(&self.solver, &self.physical);
-        match self.predictor.predict(&self.current_position, &desired) {
+        let desired = self
+            .s_curve
+            .get_desired(self.clock.as_ref(), &self.physical);
+        match self
+            .predictor
+            .predict(self.clock.as_ref(), &self.current_position, &desired)
+        {
             Prediction::Wait(_duration) => {
                 self.wait_count += 1;
                 // thread::sleep(duration);
@@ -245,24 +721,101 @@ impl Controller {
             Prediction::MoveMotors(instructions) => {
                 // print!("{},", self.wait_count);
                 self.wait_count = 0;
-                self.implement_step_instructions(instructions);
+                if let Err(msg) = self.implement_step_instructions(instructions) {
+                    self.abort_move();
+                    return Err(msg);
+                }
             }
         }
+        Ok(())
     }
-    fn implement_step_instructions(&mut self, instructions: [StepInstruction; 2]) {
+    /// Step the motors, refusing any step that would take the cords out of range
+    fn implement_step_instructions(
+        &mut self,
+        instructions: [StepInstruction; 2],
+    ) -> Result<(), &'static str> {
         let mut step: PositionStep = self.current_position.get_step().to_owned();
-        instructions
-            .iter()
-            .enumerate()
-            .for_each(|(i, instruction)| match self.motors[i].step(instruction) {
+        let mut result = Ok(());
+        for (i, instruction) in instructions.iter().enumerate() {
+            let mut next = step;
+            next.step(i, instruction);
+            if let Err(msg) = self.physical.check_step(&next) {
+                error!("refusing step to {next}: {msg}");
+                result = Err(msg);
+                break;
+            }
+            match self.motors[i].step(instruction, self.clock.as_ref()) {
                 Ok(()) => {
-                    step.step(i, instruction);
+                    step = next;
                 }
                 Err(()) => {
                     self.bad_steps_prevented += 1;
                 }
-            });
+            }
+        }
         self.current_position = Position::from_step(step, &self.physical);
+        result
+    }
+    /// Reel each cord in until its endstop closes, then take the pen to be at home
+    ///
+    /// A cord whose endstop is already closed is paid out until it opens first, and with the
+    /// position known a cord is reeled in no further than a little past where its endstop
+    /// should close, rather than pulling the carriage into the frame.
+    pub fn home(&mut self) -> Result<(), &'static str> {
+        info!("homing");
+        self.abort_move();
+        let expected = self.current_position_initialized.then(|| {
+            let home = self.physical.get_motor_dist(self.physical.get_home());
+            let step = self.current_position.get_step();
+            [0, 1].map(|i| (step[i] - home[i]).max(0) as u64)
+        });
+        self.current_position_initialized = false;
+        let margin = self.physical.mm_to_step(&HOMING_MARGIN_MM) as u64;
+        let max_cord_steps = *self.physical.get_max_cord_steps() as u64;
+        for index in 0..self.motors.len() {
+            if self.motors[index].endstop_triggered() {
+                info!("backing off the closed endstop of motor {index}");
+                self.home_motor(&index, &StepInstruction::StepLonger, &false, &margin)?;
+                self.home_motor(&index, &StepInstruction::StepShorter, &true, &margin)?;
+                continue;
+            }
+            let max_steps = expected.map_or(max_cord_steps, |steps| steps[index] + margin);
+            self.home_motor(&index, &StepInstruction::StepShorter, &true, &max_steps)?;
+        }
+        self.set_current_position(*self.physical.get_home())
+    }
+    /// Step a motor at homing speed until its endstop is `closed`, giving up after `max_steps`
+    fn home_motor(
+        &mut self,
+        index: &usize,
+        instruction: &StepInstruction,
+        closed: &bool,
+        max_steps: &u64,
+    ) -> Result<(), &'static str> {
+        let seconds_per_step = self.physical.get_homing_seconds_per_step();
+        let motor = &mut self.motors[*index];
+        let mut steps = 0;
+        let mut time_last_step = f64::NEG_INFINITY;
+        while motor.endstop_triggered() != *closed {
+            if steps > *max_steps {
+                error!("motor {index} stepped {steps} steps while homing");
+                return Err(if *closed {
+                    "Endstop did not close while homing"
+                } else {
+                    "Endstop did not open while homing"
+                });
+            }
+            self.clock.tick();
+            let now = self.clock.now();
+            if now - time_last_step < seconds_per_step {
+                continue;
+            }
+            if motor.step(instruction, self.clock.as_ref()).is_ok() {
+                steps += 1;
+                time_last_step = now;
+            }
+        }
+        Ok(())
     }
     fn move_to(&mut self) {
         if !self.current_position_initialized {
@@ -275,134 +828,302 @@ impl Controller {
         }
         let new_position = new_position.unwrap();
         info!("move from {} to {}", self.current_position, new_position);
-        self.init_move(&new_position);
+        let velocity = *self.physical.get_rapid_velocity();
+        self.init_move(&new_position, &velocity, &[]);
         loop {
             match self.move_status {
                 MoveStatus::Stopped => {
                     break;
                 }
                 MoveStatus::Moving => {
-                    self.update_move();
+                    if let Err(msg) = self.update_move() {
+                        error!("{msg}");
+                    }
                 }
             }
         }
     }
 
-    fn create_square_pattern(&self) -> Result<PlotterProgram, &'static str> {
-        println!("How long should square sides be?");
-        let square_side_length = Controller::get_scalar_from_user()?;
-        square(
-            &self.current_position.into(),
-            &square_side_length,
-            self.physical.get_max_velocity(),
-        )
+    /// Parameters for the pattern picked with `kind` from the pattern menu
+    fn pattern_from_user(&self, kind: char) -> Result<Pattern, &'static str> {
+        let pattern = match kind {
+            's' => {
+                println!("How long should square sides be?");
+                Pattern::Square {
+                    side: self.get_scalar_from_user()?,
+                }
+            }
+            't' => {
+                println!("How long should star lines be?");
+                Pattern::Star {
+                    size: self.get_scalar_from_user()?,
+                }
+            }
+            'w' => {
+                println!("Spacing?");
+                let spacing = self.get_scalar_from_user()?;
+                println!("Length?");
+                let length = self.get_scalar_from_user()?;
+                println!("Amplitude?");
+                let amplitude = self.get_scalar_from_user()?;
+                println!("Period?");
+                let period = self.get_scalar_from_user()?;
+                Pattern::Wave {
+                    spacing,
+                    length,
+                    amplitude,
+                    period,
+                }
+            }
+            'g' => {
+                println!("Radius?");
+                Pattern::Spiralgraph {
+                    radius: self.get_scalar_from_user()?,
+                }
+            }
+            'h' => {
+                println!("Size?");
+                Pattern::HeartWave {
+                    size: self.get_scalar_from_user()?,
+                }
+            }
+            x => {
+                error!("Got char {x}");
+                return Err("Got unknown option");
+            }
+        };
+        Ok(pattern)
     }
 
-    fn create_star_pattern(&self) -> Result<PlotterProgram, &'static str> {
-        println!("How long should star lines be?");
-        let size = Controller::get_scalar_from_user()?;
-        star(
-            &self.current_position.into(),
-            &size,
-            self.physical.get_max_velocity(),
-        )
+    fn load_pattern(&mut self) -> Result<(), &'static str> {
+        println!("(S)quare, s(T)ar, (W)ave, spiral(G)raph, (H)eartwave, or s(V)g file?");
+        let kind = self.get_char_from_user()?;
+        if kind == 'v' {
+            println!("Path to the svg file?");
+            let path = PathBuf::from(self.get_line_from_user()?);
+            return self.load_program(&path);
+        }
+        let pattern = self.pattern_from_user(kind)?;
+        self.program = Some(pattern.build(&self.current_position.into(), &self.physical)?);
+        self.completed = 0;
+        Ok(())
     }
 
-    fn create_wave_pattern(&self) -> Result<PlotterProgram, &'static str> {
-        println!("Spacing?");
-        let spacing = Controller::get_scalar_from_user()?;
-        println!("Length?");
-        let length = Controller::get_scalar_from_user()?;
-        println!("Amplitude?");
-        let amplitude = Controller::get_scalar_from_user()?;
-        println!("Period?");
-        let period = Controller::get_scalar_from_user()?;
-        wave(
-            &self.current_position.into(),
-            &spacing,
-            &length,
-            &amplitude,
-            &period,
-            self.physical.get_max_velocity(),
-        )
+    /// Read the job queue kept at `path`, which is saved there after every change
+    pub fn load_queue(&mut self, path: PathBuf) -> Result<(), &'static str> {
+        self.queue = JobQueue::load(&path)?;
+        if !self.queue.is_empty() {
+            info!("job queue:\n{}", self.queue);
+        }
+        self.queue_path = Some(path);
+        Ok(())
     }
-
-    fn create_spiralgraph_pattern(&self) -> Result<PlotterProgram, &'static str> {
-        println!("Radius?");
-        let radius = Controller::get_scalar_from_user()?;
-        spiralgraph(
-            &self.current_position.into(),
-            &radius,
-            self.physical.get_max_velocity(),
-        )
+    fn save_queue(&self) {
+        if let Some(path) = &self.queue_path {
+            if let Err(msg) = self.queue.save(path) {
+                error!("{msg}");
+            }
+        }
     }
-
-    fn create_heartwave_pattern(&self) -> Result<PlotterProgram, &'static str> {
-        println!("Size?");
-        let size = Controller::get_scalar_from_user()?;
-        heart_wave(
-            &self.current_position.into(),
-            &size,
-            self.physical.get_max_velocity(),
-        )
+    fn job_from_user(&self) -> Result<Job, &'static str> {
+        println!("(F)ile or (P)attern?");
+        let source = match self.get_char_from_user()? {
+            'f' => {
+                println!("Path to the gcode, svg, hpgl or dxf file?");
+                let path = PathBuf::from(self.get_line_from_user()?);
+                if !path.is_file() {
+                    return Err("No such file");
+                }
+                JobSource::File(path)
+            }
+            'p' => {
+                println!("(S)quare, s(T)ar, (W)ave, spiral(G)raph, (H)eartwave?");
+                let kind = self.get_char_from_user()?;
+                JobSource::Pattern(self.pattern_from_user(kind)?)
+            }
+            x => {
+                error!("got unsupported char {x}");
+                return Err("Got unsupported char");
+            }
+        };
+        println!("Paper limits for this job? provide \"x0,x1,y0,y1\", or nothing to use the paper limits set when it runs");
+        let paper = match self.get_line_from_user()?.as_str() {
+            "" => None,
+            text => Some(text.parse::<PaperLimits>()?),
+        };
+        println!("Fit to the paper? (N)one, (C)enter or (S)cale");
+        let fit = match self.get_char_from_user()? {
+            'n' => Fit::None,
+            'c' => Fit::Center,
+            's' => Fit::Scale,
+            x => {
+                error!("got unsupported char {x}");
+                return Err("Got unsupported char");
+            }
+        };
+        Ok(Job::new(source, paper, fit))
     }
-
-    fn load_pattern(&mut self) -> Result<(), &'static str> {
-        println!("(S)quare, s(T)ar, (W)ave, spiral(G)raph, (H)eartwave?");
-        let pattern = match Controller::get_char_from_user()? {
-            's' => self.create_square_pattern(),
-            't' => self.create_star_pattern(),
-            'w' => self.create_wave_pattern(),
-            'g' => self.create_spiralgraph_pattern(),
-            'h' => self.create_heartwave_pattern(),
+    fn edit_queue(&mut self) -> Result<(), &'static str> {
+        println!("{}", self.queue);
+        println!("(A)dd job, (R)emove job, move job (U)p, (C)lear, (S)tart, or (B)ack");
+        match self.get_char_from_user()? {
+            'a' => self.queue.push(self.job_from_user()?),
+            'r' => {
+                println!("Which job?");
+                let job = self.queue.remove(&self.get_index_from_user()?)?;
+                info!("removed {job}");
+            }
+            'u' => {
+                println!("Which job?");
+                self.queue.move_up(&self.get_index_from_user()?)?;
+            }
+            'c' => self.queue.clear(),
+            's' => {
+                if self.queue.is_empty() {
+                    return Err("No jobs queued");
+                }
+                self.queue_running = false;
+                self.mode = ControllerMode::NextJob;
+                return Ok(());
+            }
+            'b' => {
+                self.mode = ControllerMode::Ask;
+                return Ok(());
+            }
             x => {
-                error!("Got char {x}");
-                return Err("Got unknown option");
+                error!("got unsupported char {x}");
+                return Err("Got unsupported char");
             }
-        }?;
-        self.program = Some(pattern);
+        }
+        self.save_queue();
         Ok(())
     }
+    /// Start the job at the front of the queue, first waiting for the paper to be changed
+    /// if a job has just finished
+    fn next_job(&mut self) -> Result<(), &'static str> {
+        let Some(job) = self.queue.front().cloned() else {
+            info!("job queue finished");
+            self.queue_running = false;
+            self.mode = ControllerMode::Ask;
+            return Ok(());
+        };
+        if self.queue_running {
+            self.wait_between_jobs(&job)?;
+        }
+        self.queue_running = true;
+        info!("starting job {job}");
+        match job.get_source() {
+            JobSource::File(path) => {
+                self.load_program(path)?;
+            }
+            JobSource::Pattern(pattern) => {
+                self.program = Some(pattern.build(&self.current_position.into(), &self.physical)?);
+                self.completed = 0;
+            }
+        }
+        if let Some(paper) = job.get_paper() {
+            let [x_limit, y_limit] = paper.axis_limits();
+            self.set_paper_limits(x_limit, y_limit);
+        }
+        // a job without paper limits may run where it is
+        if !matches!(job.get_fit(), Fit::None) {
+            self.fit_program(job.get_fit())?;
+        }
+        self.start_program(&0, &false)
+    }
+    /// Lift the pen and wait for the operator to change the paper
+    fn wait_between_jobs(&mut self, next: &Job) -> Result<(), &'static str> {
+        match self.wait_for_operator(&format!(
+            "Job done. Change the paper for {next}, then resume."
+        )) {
+            ControlCommand::Abort => Err("Job queue stopped"),
+            _ => Ok(()),
+        }
+    }
+    /// Lift the pen if it is down
+    pub fn lift_pen(&mut self) {
+        if self.pen_down {
+            self.pen_lift.up(self.clock.as_ref());
+            self.pen_down = false;
+        }
+    }
+    /// Lift the pen, say what the operator should do and wait until they resume or abort
+    fn wait_for_operator(&mut self, message: &str) -> ControlCommand {
+        self.lift_pen();
+        self.drain_control();
+        self.save_state();
+        println!("{message}");
+        self.paused = true;
+        self.publish_status();
+        let command = self.wait_for_resume();
+        self.paused = false;
+        self.publish_status();
+        command
+    }
 
-    fn run_instruction(&mut self, instruction: &PlotterInstruction) {
+    /// upcoming: moves that follow this instruction, for the planner to look ahead at
+    pub fn run_instruction(
+        &mut self,
+        instruction: &PlotterInstruction,
+        upcoming: &[(PositionMM, Feed)],
+    ) -> Result<(), &'static str> {
         match instruction {
-            PlotterInstruction::Move(new_position) => {
-                self.init_move(new_position);
+            PlotterInstruction::Move { target, feed } => {
+                let upcoming: Vec<(PositionMM, f64)> = upcoming
+                    .iter()
+                    .map(|(target, feed)| (*target, self.physical.feed_velocity(feed)))
+                    .collect();
+                let velocity = self.physical.feed_velocity(feed);
+                self.init_move(target, &velocity, &upcoming);
                 loop {
                     match self.move_status {
                         MoveStatus::Stopped => {
                             break;
                         }
                         MoveStatus::Moving => {
-                            self.update_move();
+                            self.update_move()?;
+                            if let Ok(command) = self.control.try_recv() {
+                                if self.interrupt(command)? {
+                                    // finish the move from wherever the pen stopped
+                                    self.init_move(target, &velocity, &upcoming);
+                                }
+                            }
                         }
                     }
                 }
             }
+            PlotterInstruction::Home => self.home()?,
             PlotterInstruction::PenUp => {
-                println!("Remove pen and hit enter");
-                let _ = Controller::get_char_from_user();
+                self.pen_lift.up(self.clock.as_ref());
+                self.pen_down = false;
             }
             PlotterInstruction::PenDown => {
-                println!("Insert pen and hit enter");
-                let _ = Controller::get_char_from_user();
+                self.pen_lift.down(self.clock.as_ref());
+                self.pen_down = true;
+            }
+            PlotterInstruction::ChangePen(pen) => {
+                let message = format!("Fit {pen}, then resume.");
+                if let ControlCommand::Abort = self.wait_for_operator(&message) {
+                    info!("aborted at {}", self.current_position);
+                    return Err("Program aborted");
+                }
             }
             PlotterInstruction::Comment(c) => {
                 info!("comment: {c}");
             }
-            PlotterInstruction::NoOp => (),
         }
+        Ok(())
     }
 
-    fn get_axis_limit_from_user() -> Result<AxisLimit, &'static str> {
-        Controller::get_position_from_user().map(AxisLimit::from)
+    fn get_axis_limit_from_user(&self) -> Result<AxisLimit, &'static str> {
+        self.get_position_from_user().map(AxisLimit::from)
     }
     fn center_program(&mut self) -> Result<(), &'static str> {
         if self.program.is_none() {
             return Err("No program loaded!");
         }
         println!("Center to paper limits? (y/n)");
-        match Controller::get_char_from_user()? {
+        match self.get_char_from_user()? {
             'y' => match self.paper_limits.as_ref() {
                 Some([x_limits, y_limits]) => {
                     let prog = &mut self.program.as_mut().unwrap();
@@ -413,9 +1134,9 @@ impl Controller {
             },
             'n' => {
                 println!("What should the x limits be? (val,val)");
-                let x_limits: AxisLimit = Controller::get_axis_limit_from_user()?;
+                let x_limits: AxisLimit = self.get_axis_limit_from_user()?;
                 println!("What should the y limits be? (val,val)");
-                let y_limits: AxisLimit = Controller::get_axis_limit_from_user()?;
+                let y_limits: AxisLimit = self.get_axis_limit_from_user()?;
                 let prog = &mut self.program.as_mut().unwrap();
                 prog.center_keep_aspect(&x_limits, &y_limits)?;
                 Ok(())
@@ -426,16 +1147,60 @@ impl Controller {
             }
         }
     }
+    /// Scale or center the program to the paper limits
+    pub fn fit_program(&mut self, fit: &Fit) -> Result<(), &'static str> {
+        let Some([x_limits, y_limits]) = self.paper_limits.as_ref() else {
+            return Err("Paper limits not set");
+        };
+        let Some(program) = self.program.as_mut() else {
+            return Err("No program loaded!");
+        };
+        fit.apply(program, x_limits, y_limits)
+    }
+    /// Write the program as it would be plotted, after any scaling or centering, to gcode
+    fn export_program(&self) -> Result<(), &'static str> {
+        let Some(program) = self.program.as_ref() else {
+            return Err("No program loaded!");
+        };
+        println!("Path to write the gcode to?");
+        let path = PathBuf::from(self.get_line_from_user()?);
+        println!("Write feed rates? (y/n)");
+        let feed_rates = self.get_char_from_user()? == 'y';
+        program.write_gcode_file(&path, &feed_rates, self.physical.get_max_velocity())?;
+        info!("wrote {}", path.display());
+        Ok(())
+    }
+    /// Draw the program as it would be plotted over the paper and machine limits
+    fn preview_program(&self) -> Result<(), &'static str> {
+        let Some(program) = self.program.as_ref() else {
+            return Err("No program loaded!");
+        };
+        println!("Path to write the preview to? (.svg or .png)");
+        let path = PathBuf::from(self.get_line_from_user()?);
+        println!("Show pen up travel? (y/n)");
+        let travel = self.get_char_from_user()? == 'y';
+        println!("Color by drawing order? (y/n)");
+        let gradient = self.get_char_from_user()? == 'y';
+        preview(
+            program,
+            &self.physical,
+            self.paper_limits.as_ref(),
+            &travel,
+            &gradient,
+            &DEFAULT_PX_PER_MM,
+            &path,
+        )
+    }
     fn scale_program(&mut self) -> Result<(), &'static str> {
         if self.program.is_none() {
             return Err("No program loaded!");
         }
         println!("What should the x limits be? (val,val)");
-        let x_limits: AxisLimit = Controller::get_axis_limit_from_user()?;
+        let x_limits: AxisLimit = self.get_axis_limit_from_user()?;
         println!("What should the y limits be? (val,val)");
-        let y_limits: AxisLimit = Controller::get_axis_limit_from_user()?;
+        let y_limits: AxisLimit = self.get_axis_limit_from_user()?;
         println!("Preserve aspect Ratio? (y,n)");
-        let reply = Controller::get_char_from_user()?;
+        let reply = self.get_char_from_user()?;
         match reply {
             'y' => {
                 let prog = &mut self.program.as_mut().unwrap();
@@ -474,7 +1239,80 @@ impl Controller {
         }
     }
 
+    fn resume_program(&mut self) -> Result<(), &'static str> {
+        println!(
+            "Resume from which instruction? (last finished: {})",
+            self.completed
+        );
+        let index = self.get_scalar_from_user()?;
+        if index < 0.0 || index.fract() != 0.0 {
+            return Err("Instruction index must be a whole number");
+        }
+        println!("Back up to the last pen lift? (y/n)");
+        let back_up = self.get_char_from_user()? == 'y';
+        self.start_program(&(index as usize), &back_up)
+    }
+
+    /// Check the program fits the paper and start running it from instruction `from`
+    ///
+    /// Starting part way through lifts the pen and travels to where that instruction starts.
+    /// back_up: start from the last pen lift at or before `from` instead
+    pub fn start_program(&mut self, from: &usize, back_up: &bool) -> Result<(), &'static str> {
+        // commands sent while nothing was running are not meant for this program
+        self.drain_control();
+        self.init_program()?;
+        let program = self.program.as_mut().unwrap();
+        let index = if *back_up {
+            program.last_lift_before(from)
+        } else {
+            *from
+        };
+        program.seek(&index)?;
+        if index > 0 {
+            let (target, pen_down) = program.state_before_current();
+            info!("resuming at instruction {index}/{}", program.len());
+            self.run_instruction(&PlotterInstruction::PenUp, &[])?;
+            if let Some(target) = target {
+                let travel = PlotterInstruction::Move {
+                    target,
+                    feed: Feed::Rapid,
+                };
+                self.run_instruction(&travel, &[])?;
+            }
+            if pen_down {
+                self.run_instruction(&PlotterInstruction::PenDown, &[])?;
+            }
+        }
+        self.completed = index;
+        self.mode = ControllerMode::RunProgram;
+        self.publish_status();
+        Ok(())
+    }
+
+    /// A program is running or paused
+    pub fn is_running_program(&self) -> bool {
+        matches!(self.mode, ControllerMode::RunProgram)
+    }
+
+    /// Every instruction of the loaded program has finished
+    pub fn program_finished(&self) -> bool {
+        self.program
+            .as_ref()
+            .is_some_and(|program| self.completed == program.len())
+    }
+
+    /// Run the current mode once, saving the session when it goes back to the menu after a
+    /// program or menu action finishes or stops
     pub fn update(&mut self) {
+        let busy = !matches!(self.mode, ControllerMode::Ask);
+        self.update_mode();
+        self.publish_status();
+        if busy && matches!(self.mode, ControllerMode::Ask) {
+            self.save_state();
+        }
+    }
+
+    fn update_mode(&mut self) {
         match self.mode {
             ControllerMode::Ask => {
                 self.set_mode_from_user();
@@ -483,6 +1321,12 @@ impl Controller {
                 self.move_to();
                 self.mode = ControllerMode::Ask;
             }
+            ControllerMode::Home => {
+                if let Err(msg) = self.home() {
+                    error!("{msg}");
+                }
+                self.mode = ControllerMode::Ask;
+            }
             ControllerMode::QueryPaper => match self.set_paper_limits_from_user() {
                 Ok(_) => {
                     self.mode = ControllerMode::Ask;
@@ -510,32 +1354,49 @@ impl Controller {
                 }
                 self.mode = ControllerMode::Ask;
             }
-            ControllerMode::InitProgram => match self.init_program() {
-                Ok(_) => {
-                    self.mode = ControllerMode::RunProgram;
+            ControllerMode::InitProgram => {
+                if let Err(msg) = self.start_program(&0, &false) {
+                    error!("{msg}");
+                    self.mode = ControllerMode::Ask;
                 }
-                Err(msg) => {
+            }
+            ControllerMode::ResumeProgram => {
+                if let Err(msg) = self.resume_program() {
                     error!("{msg}");
                     self.mode = ControllerMode::Ask;
                 }
-            },
+            }
             ControllerMode::RunProgram => match self.program.as_mut() {
                 Some(program) => {
                     info!(
                         "Run instruction: {}/{}, remaining: {}/{}, bad_prevented: {}",
                         program.current_position(),
                         program.len(),
-                        format_time(match program.time_remaining_next_lift() {
-                            Some(t_next) => {
-                                t_next
-                            }
-                            None => std::f64::INFINITY,
-                        }),
+                        format_time(program.time_remaining_next_lift().unwrap_or(f64::INFINITY)),
                         format_time(*program.time_remaining()),
                         self.bad_steps_prevented
                     );
                     match program.next() {
-                        Some(instruction) => self.run_instruction(&instruction),
+                        Some(instruction) => {
+                            let index = program.current_position();
+                            let upcoming = program.upcoming_moves(self.planner.get_look_ahead());
+                            match self.run_instruction(&instruction, &upcoming) {
+                                Ok(()) => {
+                                    self.completed = index;
+                                }
+                                Err(msg) => {
+                                    error!("{msg}");
+                                    error!("Stopping program");
+                                    self.queue_running = false;
+                                    self.mode = ControllerMode::Ask;
+                                }
+                            }
+                        }
+                        None if self.queue_running => {
+                            self.queue.pop_front();
+                            self.save_queue();
+                            self.mode = ControllerMode::NextJob;
+                        }
                         None => {
                             self.mode = ControllerMode::Ask;
                         }
@@ -562,6 +1423,199 @@ impl Controller {
                     error!("{msg}");
                 }
             },
+            ControllerMode::EditQueue => {
+                if let Err(msg) = self.edit_queue() {
+                    error!("{msg}");
+                }
+            }
+            ControllerMode::NextJob => {
+                if let Err(msg) = self.next_job() {
+                    error!("{msg}");
+                    self.queue_running = false;
+                    self.mode = ControllerMode::Ask;
+                }
+            }
+            ControllerMode::ExportProgram => {
+                if let Err(msg) = self.export_program() {
+                    error!("{msg}");
+                }
+                self.mode = ControllerMode::Ask;
+            }
+            ControllerMode::PreviewProgram => {
+                if let Err(msg) = self.preview_program() {
+                    error!("{msg}");
+                }
+                self.mode = ControllerMode::Ask;
+            }
+            ControllerMode::Quit => {}
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::VirtualClock, motor::Endstop};
+
+    /// Recording motors on `hardware` that allow a step every `seconds_per_step`
+    fn recording_motors(hardware: &SimulatedHardware, seconds_per_step: f64) -> [Motor; 2] {
+        let make_motor = |side| {
+            Motor::new(
+                side,
+                seconds_per_step,
+                DriverKind::Recording.build(side, hardware),
+                DriverKind::Recording.build_endstop(side, hardware),
+            )
+        };
+        [make_motor(Side::Left), make_motor(Side::Right)]
+    }
+
+    /// Controller of the default machine on a virtual clock advancing `tick` seconds a pass
+    fn recording_controller(motors: [Motor; 2], tick: f64) -> Controller {
+        let physical = Physical::default();
+        let pen_lift = DriverKind::Recording.build_pen_lift(&physical);
+        let clock = Arc::new(VirtualClock::new(tick));
+        Controller::with_hardware(physical, motors, pen_lift, clock, None)
+    }
+
+    /// Run moves on recording motors that allow a step every `seconds_per_step`, returning
+    /// the steps prevented and where the pen ended up
+    fn run_moves(seconds_per_step: f64, targets: &[[f64; 2]]) -> (u64, PositionMM) {
+        let physical = Physical::default();
+        let start = *physical.get_home();
+        let hardware = SimulatedHardware::new(&start, &physical);
+        let motors = recording_motors(&hardware, seconds_per_step);
+        let mut controller = recording_controller(motors, 1e-4);
+        controller.set_current_position(start).unwrap();
+        for target in targets {
+            let target = PositionMM::new(*target);
+            let feed = Feed::Max;
+            let instruction = PlotterInstruction::Move { target, feed };
+            controller.run_instruction(&instruction, &[]).unwrap();
+        }
+        (
+            controller.bad_steps_prevented,
+            controller.current_position.into(),
+        )
+    }
+
+    #[test]
+    fn bad_steps_prevented_is_reproducible() {
+        let physical = Physical::default();
+        let min_seconds_per_step = *physical.get_min_seconds_per_step();
+        let targets = [[140.0, 290.0], [150.0, 295.0]];
+        let (prevented, end) = run_moves(min_seconds_per_step, &targets);
+        let (again, again_end) = run_moves(min_seconds_per_step, &targets);
+        assert_eq!(
+            (again, again_end.x(), again_end.y()),
+            (prevented, end.x(), end.y())
+        );
+        assert!(end.dist(&PositionMM::new(targets[1])) < 0.5);
+
+        // motors slower than the profile says can not keep up, and steps are refused
+        let (slow_prevented, _) = run_moves(min_seconds_per_step * 2.0, &targets);
+        assert!(slow_prevented > prevented);
+    }
+
+    /// Home with the pen really at `actual`, and believed to be at `believed` if given,
+    /// returning the result and the steps each motor took in and out
+    fn home_from(
+        actual: [f64; 2],
+        believed: Option<[f64; 2]>,
+    ) -> (Result<(), &'static str>, Controller, [[u64; 2]; 2]) {
+        let physical = Physical::default();
+        let hardware = SimulatedHardware::new(&PositionMM::new(actual), &physical);
+        let motors = recording_motors(&hardware, *physical.get_min_seconds_per_step());
+        let mut controller = recording_controller(motors, 1e-3);
+        if let Some(xy) = believed {
+            controller
+                .set_current_position(PositionMM::new(xy))
+                .unwrap();
+        }
+        let result = controller.home();
+        let mut steps = [[0; 2]; 2];
+        for (side, rotation) in hardware.journal().lock().unwrap().iter() {
+            match side.step_instruction(rotation) {
+                StepInstruction::StepShorter => steps[side.index()][0] += 1,
+                StepInstruction::StepLonger => steps[side.index()][1] += 1,
+                StepInstruction::Hold => {}
+            }
+        }
+        (result, controller, steps)
+    }
+
+    /// Cords in steps after taking `steps` from `start`
+    fn cords_after(start: [f64; 2], steps: &[[u64; 2]; 2]) -> [i64; 2] {
+        let physical = Physical::default();
+        let cords = physical.get_motor_dist(&PositionMM::new(start));
+        [0, 1].map(|i| cords[i] - steps[i][0] as i64 + steps[i][1] as i64)
+    }
+
+    #[test]
+    fn homing_backs_off_a_closed_endstop() {
+        let physical = Physical::default();
+        let home = physical.get_motor_dist(physical.get_home());
+        // above home both cords are shorter than where their endstops close
+        let above = [148.5, 320.0];
+        let (result, controller, steps) = home_from(above, None);
+        result.unwrap();
+        assert!(controller.position_known());
+        for (i, [_, paid_out]) in steps.iter().enumerate() {
+            assert!(*paid_out > 0, "motor {i} did not pay out");
+        }
+        assert_eq!(cords_after(above, &steps), [home[0], home[1]]);
+    }
+
+    #[test]
+    fn homing_stops_a_little_past_the_expected_cord_length() {
+        let physical = Physical::default();
+        let home = physical.get_motor_dist(physical.get_home());
+        let below = [148.5, 280.0];
+        let (result, controller, steps) = home_from(below, Some(below));
+        result.unwrap();
+        assert!(controller.position_known());
+        assert_eq!(cords_after(below, &steps), [home[0], home[1]]);
+
+        // believed to be 20 mm below home but really 100 mm below, it gives up well before
+        // reeling the carriage all the way in
+        let believed = physical.get_motor_dist(&PositionMM::new([148.5, 280.0]));
+        let margin = physical.mm_to_step(&HOMING_MARGIN_MM) as u64;
+        let (result, controller, steps) = home_from([148.5, 200.0], Some([148.5, 280.0]));
+        assert_eq!(result, Err("Endstop did not close while homing"));
+        assert!(!controller.position_known());
+        let bound = (believed[0] - home[0]) as u64 + margin + 1;
+        assert_eq!(steps[0], [bound, 0]);
+        // the second motor is not tried once the first fails
+        assert_eq!(steps[1], [0, 0]);
+    }
+
+    /// Switch that is never closed, as when it is unplugged
+    struct OpenEndstop;
+
+    impl Endstop for OpenEndstop {
+        fn triggered(&mut self) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn homing_fails_when_the_endstop_never_closes() {
+        let physical = Physical::default();
+        let hardware = SimulatedHardware::new(physical.get_home(), &physical);
+        let make_motor = |side| {
+            Motor::new(
+                side,
+                *physical.get_min_seconds_per_step(),
+                DriverKind::Recording.build(side, &hardware),
+                Box::new(OpenEndstop),
+            )
+        };
+        let motors = [make_motor(Side::Left), make_motor(Side::Right)];
+        let mut controller = recording_controller(motors, 1e-3);
+        assert_eq!(controller.home(), Err("Endstop did not close while homing"));
+        assert!(!controller.position_known());
+        // with the position unknown it reels in no more than the longest cord
+        let steps = hardware.journal().lock().unwrap().len() as u64;
+        assert_eq!(steps, *physical.get_max_cord_steps() as u64 + 1);
+    }
+}
@@ -1,12 +1,15 @@
+use std::fmt::Display;
+
 use nalgebra::{Point2, Rotation2, Vector2};
 use ndarray::prelude::*;
+use serde::{Deserialize, Serialize};
 
-use crate::{gcode::PlotterProgram, position::PositionMM};
+use crate::{gcode::PlotterProgram, physical::Physical, position::PositionMM};
 
 pub fn square(
     position: &PositionMM,
     side_length: &f64,
-    max_velocity: &f64,
+    physical: &Physical,
 ) -> Result<PlotterProgram, &'static str> {
     PlotterProgram::from_positions(
         position,
@@ -16,14 +19,14 @@ pub fn square(
             position.offset(side_length, &[1.0, 0.0]),
             position.offset(side_length, &[0.0, 0.0]),
         ],
-        max_velocity,
+        physical,
     )
 }
 
 pub fn star(
     position: &PositionMM,
     side_length: &f64,
-    max_velocity: &f64,
+    physical: &Physical,
 ) -> Result<PlotterProgram, &'static str> {
     let n = 13;
     let mut p: Point2<f64> = (*position).into();
@@ -44,7 +47,7 @@ pub fn star(
         ptr %= n;
         hist.push(hist[ptr]);
     }
-    PlotterProgram::from_positions(position, hist, max_velocity)
+    PlotterProgram::from_positions(position, hist, physical)
 }
 
 pub fn wave(
@@ -53,7 +56,7 @@ pub fn wave(
     length: &f64,
     amplitude: &f64,
     period: &f64,
-    max_velocity: &f64,
+    physical: &Physical,
 ) -> Result<PlotterProgram, &'static str> {
     let n = (length / spacing) as usize;
     let mut pts = Vec::new();
@@ -63,13 +66,13 @@ pub fn wave(
         let y = (x * y_scale).sin() * amplitude / 2.0;
         pts.push(PositionMM::new([x + position.x(), y + position.y()]));
     }
-    PlotterProgram::from_positions(position, pts, max_velocity)
+    PlotterProgram::from_positions(position, pts, physical)
 }
 
 pub fn spiralgraph(
     position: &PositionMM,
     radius: &f64,
-    max_velocity: &f64,
+    physical: &Physical,
 ) -> Result<PlotterProgram, &'static str> {
     let n = 1800;
     let dr = 1.0_f64.to_radians();
@@ -83,13 +86,13 @@ pub fn spiralgraph(
         let y = r * t.sin();
         pts.push(PositionMM::new([x + position.x(), y + position.y()]));
     }
-    PlotterProgram::from_positions(position, pts, max_velocity)
+    PlotterProgram::from_positions(position, pts, physical)
 }
 
 pub fn heart_wave(
     position: &PositionMM,
     size: &f64,
-    max_velocity: &f64,
+    physical: &Physical,
 ) -> Result<PlotterProgram, &'static str> {
     let a = 20.0;
     let x_arr: Array1<f64> = Array::linspace(-2.0, 2.0, 500);
@@ -114,5 +117,67 @@ pub fn heart_wave(
         let y = (pt.y() - pts[0].y()) * scale + position.y();
         pts2.push(PositionMM::new([x, y]));
     }
-    PlotterProgram::from_positions(position, pts2, max_velocity)
+    PlotterProgram::from_positions(position, pts2, physical)
+}
+
+/// A pattern and its parameters, drawn around wherever the pen is when it is built
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Pattern {
+    Square {
+        side: f64,
+    },
+    Star {
+        size: f64,
+    },
+    Wave {
+        spacing: f64,
+        length: f64,
+        amplitude: f64,
+        period: f64,
+    },
+    Spiralgraph {
+        radius: f64,
+    },
+    HeartWave {
+        size: f64,
+    },
+}
+
+impl Pattern {
+    pub fn build(
+        &self,
+        position: &PositionMM,
+        physical: &Physical,
+    ) -> Result<PlotterProgram, &'static str> {
+        match self {
+            Pattern::Square { side } => square(position, side, physical),
+            Pattern::Star { size } => star(position, size, physical),
+            Pattern::Wave {
+                spacing,
+                length,
+                amplitude,
+                period,
+            } => wave(position, spacing, length, amplitude, period, physical),
+            Pattern::Spiralgraph { radius } => spiralgraph(position, radius, physical),
+            Pattern::HeartWave { size } => heart_wave(position, size, physical),
+        }
+    }
+}
+
+impl Display for Pattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Pattern::Square { side } => write!(f, "square {side}"),
+            Pattern::Star { size } => write!(f, "star {size}"),
+            Pattern::Wave {
+                spacing,
+                length,
+                amplitude,
+                period,
+            } => write!(f, "wave {spacing}, {length}, {amplitude}, {period}"),
+            Pattern::Spiralgraph { radius } => write!(f, "spiralgraph {radius}"),
+            Pattern::HeartWave { size } => write!(f, "heartwave {size}"),
+        }
+    }
 }
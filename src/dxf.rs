@@ -0,0 +1,486 @@
+use std::{collections::BTreeSet, fs, path::Path};
+
+use log::{error, info, warn};
+use nalgebra::{Vector2, Vector3};
+
+use crate::{
+    gcode::{arc_to_chords, Feed, PlotterInstruction, ReadOptions},
+    position::PositionMM,
+};
+
+/// Most times a spline knot span is halved while flattening it
+const MAX_SPLINE_DEPTH: u32 = 16;
+
+/// Entities of an ascii dxf file as pen moves, in mm
+///
+/// LINE, LWPOLYLINE, POLYLINE, ARC, CIRCLE and SPLINE are drawn; anything else is skipped with
+/// a warning. Only the layers named in the options are drawn, or every layer if none are. With
+/// layer passes each layer is drawn in turn, pausing for a pen change before the next one.
+pub fn read_dxf(
+    path: &Path,
+    options: &ReadOptions,
+) -> Result<Vec<PlotterInstruction>, &'static str> {
+    let data = fs::read(path).map_err(|e| {
+        error!("{}: {e}", path.display());
+        "Failed to read dxf file"
+    })?;
+    if data.starts_with(b"AutoCAD Binary DXF") {
+        return Err("Binary dxf files are not supported");
+    }
+    let text = String::from_utf8_lossy(&data);
+    let pairs = pairs(&text)?;
+    let scale = mm_per_unit(&pairs);
+    // curves are flattened in drawing units
+    let tolerance = options.get_tolerance() / scale;
+    let mut ignored = BTreeSet::new();
+    let mut strokes = Vec::new();
+    for entity in entities(&pairs) {
+        match entity.points(&tolerance)? {
+            Some(points) => strokes.push((entity.layer().to_owned(), points)),
+            None => {
+                ignored.insert(entity.kind);
+            }
+        }
+    }
+    if !ignored.is_empty() {
+        warn!("ignored unsupported dxf entities: {ignored:?}");
+    }
+
+    // layers in the order they are drawn
+    let mut layers = options.get_layers().clone();
+    if layers.is_empty() {
+        for (layer, _) in &strokes {
+            if !layers.contains(layer) {
+                layers.push(layer.clone());
+            }
+        }
+    }
+    for layer in &layers {
+        if !strokes.iter().any(|(l, _)| l == layer) {
+            warn!("nothing to draw on dxf layer {layer}");
+        }
+    }
+    strokes.retain(|(layer, _)| layers.contains(layer));
+    if *options.get_layer_passes() {
+        // stable, so entities keep their order within a layer
+        strokes.sort_by_key(|(layer, _)| layers.iter().position(|l| l == layer));
+    }
+
+    let mut drawing = Drawing::default();
+    for (layer, points) in &strokes {
+        if *options.get_layer_passes() && drawing.layer.as_ref() != Some(layer) {
+            if drawing.layer.is_some() {
+                drawing
+                    .instructions
+                    .push(PlotterInstruction::ChangePen(format!(
+                        "the pen for layer {layer}"
+                    )));
+                drawing.last = None;
+            }
+            info!(
+                "dxf layer {layer} starts at instruction {}",
+                drawing.instructions.len()
+            );
+            drawing.layer = Some(layer.clone());
+        }
+        drawing.stroke(points.iter().map(|p| p * scale));
+    }
+    if drawing.instructions.is_empty() {
+        return Err("Nothing to draw in dxf file");
+    }
+    drawing.instructions.push(PlotterInstruction::PenUp);
+    Ok(drawing.instructions)
+}
+
+/// Group code and value pairs, which take two lines each
+fn pairs(text: &str) -> Result<Vec<(i32, &str)>, &'static str> {
+    let mut lines = text.lines();
+    let mut pairs = Vec::new();
+    while let Some(code) = lines.next() {
+        if code.trim().is_empty() {
+            continue;
+        }
+        let code = code.trim().parse().map_err(|_| {
+            error!("dxf group code {code}");
+            "Failed to parse dxf group code"
+        })?;
+        let value = lines.next().ok_or("Dxf file ends in a group code")?;
+        pairs.push((code, value.trim()));
+    }
+    Ok(pairs)
+}
+
+/// Scale of the drawing units set by $INSUNITS, which are taken to be mm when not set
+fn mm_per_unit(pairs: &[(i32, &str)]) -> f64 {
+    let units = pairs
+        .windows(2)
+        .find(|w| w[0] == (9, "$INSUNITS"))
+        .and_then(|w| w[1].1.parse::<i32>().ok())
+        .unwrap_or(0);
+    match units {
+        0 | 4 => 1.0,
+        1 => 25.4,
+        2 => 304.8,
+        5 => 10.0,
+        6 => 1000.0,
+        9 => 0.0254,
+        _ => {
+            warn!("unsupported dxf units {units}, taking them to be mm");
+            1.0
+        }
+    }
+}
+
+/// Entities of the ENTITIES section, with the vertices of each POLYLINE gathered into it
+fn entities<'a>(pairs: &[(i32, &'a str)]) -> Vec<Entity<'a>> {
+    let mut flat: Vec<Entity> = Vec::new();
+    let mut in_entities = false;
+    for (i, &(code, value)) in pairs.iter().enumerate() {
+        match (code, value) {
+            (0, "SECTION") => in_entities = pairs.get(i + 1) == Some(&(2, "ENTITIES")),
+            (0, "ENDSEC") => in_entities = false,
+            _ if !in_entities => {}
+            (0, kind) => flat.push(Entity::new(kind)),
+            _ => {
+                if let Some(entity) = flat.last_mut() {
+                    entity.pairs.push((code, value));
+                }
+            }
+        }
+    }
+    let mut entities: Vec<Entity> = Vec::new();
+    for entity in flat {
+        match entity.kind {
+            "VERTEX" | "SEQEND" if entities.last().is_some_and(|e| e.kind == "POLYLINE") => {
+                if entity.kind == "VERTEX" {
+                    entities.last_mut().unwrap().vertices.push(entity);
+                }
+            }
+            _ => entities.push(entity),
+        }
+    }
+    entities
+}
+
+struct Entity<'a> {
+    kind: &'a str,
+    pairs: Vec<(i32, &'a str)>,
+    /// VERTEX entities of a POLYLINE
+    vertices: Vec<Entity<'a>>,
+}
+
+impl<'a> Entity<'a> {
+    fn new(kind: &'a str) -> Self {
+        Entity {
+            kind,
+            pairs: Vec::new(),
+            vertices: Vec::new(),
+        }
+    }
+    fn layer(&self) -> &'a str {
+        self.pairs
+            .iter()
+            .find(|(code, _)| *code == 8)
+            .map_or("0", |(_, layer)| layer)
+    }
+    /// Every value of a group code, in order
+    fn numbers(&self, code: i32) -> Result<Vec<f64>, &'static str> {
+        self.pairs
+            .iter()
+            .filter(|(c, _)| *c == code)
+            .map(|(_, value)| {
+                value.parse().map_err(|_| {
+                    error!("{} group {code}: {value}", self.kind);
+                    "Failed to parse dxf value"
+                })
+            })
+            .collect()
+    }
+    fn number(&self, code: i32) -> Result<Option<f64>, &'static str> {
+        Ok(self.numbers(code)?.first().copied())
+    }
+    fn required(&self, code: i32) -> Result<f64, &'static str> {
+        self.number(code)?.ok_or_else(|| {
+            error!("{} has no group {code}", self.kind);
+            "Missing dxf value"
+        })
+    }
+    /// The point whose x has group code `code`, with y 10 codes on
+    fn point(&self, code: i32) -> Result<Vector2<f64>, &'static str> {
+        Ok(Vector2::new(
+            self.required(code)?,
+            self.required(code + 10)?,
+        ))
+    }
+    fn closed(&self) -> Result<bool, &'static str> {
+        Ok(self.number(70)?.is_some_and(|flags| flags as i32 & 1 == 1))
+    }
+    /// Points along the entity in drawing units, or None if it is not a kind that is drawn
+    fn points(&self, tolerance: &f64) -> Result<Option<Vec<Vector2<f64>>>, &'static str> {
+        let mut points = match self.kind {
+            "LINE" => vec![self.point(10)?, self.point(11)?],
+            "CIRCLE" => {
+                let center = self.point(10)?;
+                let start = center + Vector2::new(self.required(40)?, 0.0);
+                let mut points = vec![start];
+                points.extend(chords(&start, &start, &center, false, tolerance));
+                points
+            }
+            "ARC" => {
+                let center = self.point(10)?;
+                let radius = self.required(40)?;
+                let at = |angle: f64| {
+                    let angle = angle.to_radians();
+                    center + radius * Vector2::new(angle.cos(), angle.sin())
+                };
+                let start = at(self.required(50)?);
+                let end = at(self.required(51)?);
+                let mut points = vec![start];
+                points.extend(chords(&start, &end, &center, false, tolerance));
+                points
+            }
+            "LWPOLYLINE" => {
+                // vertices are a run of 10, 20 and an optional 42 bulge
+                let mut vertices: Vec<(Vector2<f64>, f64)> = Vec::new();
+                for &(code, value) in &self.pairs {
+                    let number = || {
+                        value.parse::<f64>().map_err(|_| {
+                            error!("{} group {code}: {value}", self.kind);
+                            "Failed to parse dxf value"
+                        })
+                    };
+                    match (code, vertices.last_mut()) {
+                        (10, _) => vertices.push((Vector2::new(number()?, 0.0), 0.0)),
+                        (20, Some(vertex)) => vertex.0.y = number()?,
+                        (42, Some(vertex)) => vertex.1 = number()?,
+                        _ => {}
+                    }
+                }
+                bulged(&vertices, self.closed()?, tolerance)
+            }
+            "POLYLINE" => {
+                let vertices = self
+                    .vertices
+                    .iter()
+                    .map(|vertex| Ok((vertex.point(10)?, vertex.number(42)?.unwrap_or(0.0))))
+                    .collect::<Result<Vec<_>, &'static str>>()?;
+                bulged(&vertices, self.closed()?, tolerance)
+            }
+            "SPLINE" => self.spline(tolerance)?,
+            _ => return Ok(None),
+        };
+        // 2d entities are drawn in a plane whose normal may point down, which mirrors x
+        if self.kind != "LINE"
+            && self.kind != "SPLINE"
+            && self.number(230)?.is_some_and(|z| z < 0.0)
+        {
+            for point in &mut points {
+                point.x = -point.x;
+            }
+        }
+        Ok(Some(points))
+    }
+    /// Control points and knots of a NURBS curve, or the fit points if it has no control points
+    fn spline(&self, tolerance: &f64) -> Result<Vec<Vector2<f64>>, &'static str> {
+        let xs = self.numbers(10)?;
+        let ys = self.numbers(20)?;
+        if xs.is_empty() {
+            let fit: Vec<Vector2<f64>> = self
+                .numbers(11)?
+                .into_iter()
+                .zip(self.numbers(21)?)
+                .map(|(x, y)| Vector2::new(x, y))
+                .collect();
+            if fit.len() < 2 {
+                return Err("Dxf spline has no control points");
+            }
+            warn!("drawing straight through the fit points of a spline without control points");
+            return Ok(fit);
+        }
+        let degree = self.required(71)? as usize;
+        let knots = self.numbers(40)?;
+        let weights = self.numbers(41)?;
+        if degree == 0
+            || xs.len() != ys.len()
+            || xs.len() <= degree
+            || knots.len() != xs.len() + degree + 1
+            || !(weights.is_empty() || weights.len() == xs.len())
+            || knots.windows(2).any(|w| w[1] < w[0])
+        {
+            error!(
+                "spline of degree {degree} with {} control points, {} knots and {} weights",
+                xs.len(),
+                knots.len(),
+                weights.len()
+            );
+            return Err("Malformed dxf spline");
+        }
+        let controls = (0..xs.len())
+            .map(|i| {
+                let weight = weights.get(i).copied().unwrap_or(1.0);
+                Vector3::new(xs[i] * weight, ys[i] * weight, weight)
+            })
+            .collect();
+        let spline = Spline {
+            degree,
+            knots,
+            controls,
+        };
+        let mut points = vec![spline.at(spline.knots[degree])];
+        for span in degree..xs.len() {
+            let [start, end] = [spline.knots[span], spline.knots[span + 1]];
+            if end > start {
+                spline.flatten(start, end, tolerance, 0, &mut points);
+            }
+        }
+        Ok(points)
+    }
+}
+
+/// Chord ends of an arc, without its start
+fn chords(
+    start: &Vector2<f64>,
+    end: &Vector2<f64>,
+    center: &Vector2<f64>,
+    clockwise: bool,
+    tolerance: &f64,
+) -> Vec<Vector2<f64>> {
+    let mm = |v: &Vector2<f64>| PositionMM::new([v.x, v.y]);
+    arc_to_chords(&mm(start), &mm(end), &mm(center), clockwise, tolerance)
+        .iter()
+        .map(|p| Vector2::new(*p.x(), *p.y()))
+        .collect()
+}
+
+/// Points of a polyline whose vertices carry the bulge of the segment that starts at them
+///
+/// The bulge is the tangent of a quarter of the angle an arc segment turns through, negative
+/// when it turns clockwise, and 0 for a straight segment.
+fn bulged(vertices: &[(Vector2<f64>, f64)], closed: bool, tolerance: &f64) -> Vec<Vector2<f64>> {
+    let Some((first, _)) = vertices.first() else {
+        return Vec::new();
+    };
+    let mut points = vec![*first];
+    let segments = if closed {
+        vertices.len()
+    } else {
+        vertices.len() - 1
+    };
+    for i in 0..segments {
+        let (start, bulge) = vertices[i];
+        let end = vertices[(i + 1) % vertices.len()].0;
+        if bulge == 0.0 || start == end {
+            points.push(end);
+            continue;
+        }
+        let chord = end - start;
+        let left = Vector2::new(-chord.y, chord.x);
+        let center = start + chord / 2.0 + left * (1.0 - bulge * bulge) / (4.0 * bulge);
+        points.extend(chords(&start, &end, &center, bulge < 0.0, tolerance));
+    }
+    points
+}
+
+/// Distance from `point` to the segment from `start` to `end`
+fn distance_to_segment(point: &Vector2<f64>, start: &Vector2<f64>, end: &Vector2<f64>) -> f64 {
+    let segment = end - start;
+    let length_squared = segment.norm_squared();
+    let t = if length_squared > 0.0 {
+        ((point - start).dot(&segment) / length_squared).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    (point - (start + t * segment)).norm()
+}
+
+/// Rational B-spline with control points weighted as (w x, w y, w)
+struct Spline {
+    degree: usize,
+    knots: Vec<f64>,
+    controls: Vec<Vector3<f64>>,
+}
+
+impl Spline {
+    /// Point at parameter `u`, by de Boor's algorithm
+    fn at(&self, u: f64) -> Vector2<f64> {
+        let p = self.degree;
+        let mut k = p;
+        while k + 1 < self.controls.len() && self.knots[k + 1] <= u {
+            k += 1;
+        }
+        let mut d = self.controls[k - p..=k].to_vec();
+        for r in 1..=p {
+            for j in (r..=p).rev() {
+                let i = j + k - p;
+                let span = self.knots[i + p + 1 - r] - self.knots[i];
+                let alpha = if span > 0.0 {
+                    (u - self.knots[i]) / span
+                } else {
+                    0.0
+                };
+                d[j] = d[j - 1] * (1.0 - alpha) + d[j] * alpha;
+            }
+        }
+        Vector2::new(d[p].x / d[p].z, d[p].y / d[p].z)
+    }
+    /// Push chord ends from the last point to the one at `end`, halving until the middle of
+    /// each piece is within tolerance of its chord
+    fn flatten(
+        &self,
+        start: f64,
+        end: f64,
+        tolerance: &f64,
+        depth: u32,
+        points: &mut Vec<Vector2<f64>>,
+    ) {
+        let from = *points.last().unwrap();
+        let to = self.at(end);
+        let middle = (start + end) / 2.0;
+        // always halve a couple of times so an s bend is not taken for a straight line
+        if depth < MAX_SPLINE_DEPTH
+            && (depth < 2 || distance_to_segment(&self.at(middle), &from, &to) > *tolerance)
+        {
+            self.flatten(start, middle, tolerance, depth + 1, points);
+            self.flatten(middle, end, tolerance, depth + 1, points);
+        } else {
+            points.push(to);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Drawing {
+    instructions: Vec<PlotterInstruction>,
+    /// End of the last stroke in mm, if the pen is still down there
+    last: Option<Vector2<f64>>,
+    /// Layer being drawn, when each layer is its own pass
+    layer: Option<String>,
+}
+
+impl Drawing {
+    /// Draw through points in mm, lifting the pen first unless it carries on from the last stroke
+    fn stroke(&mut self, points: impl Iterator<Item = Vector2<f64>>) {
+        for (i, point) in points.enumerate() {
+            let target = PositionMM::new([point.x, point.y]);
+            if i == 0 {
+                if self.last.is_some_and(|last| (last - point).norm() < 1e-6) {
+                    continue;
+                }
+                self.instructions.extend([
+                    PlotterInstruction::PenUp,
+                    PlotterInstruction::Move {
+                        target,
+                        feed: Feed::Rapid,
+                    },
+                    PlotterInstruction::PenDown,
+                ]);
+            } else {
+                self.instructions.push(PlotterInstruction::Move {
+                    target,
+                    feed: Feed::Max,
+                });
+            }
+            self.last = Some(point);
+        }
+    }
+}
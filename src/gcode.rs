@@ -1,16 +1,21 @@
 // use anyhow::Result;
 use std::{
+    cell::Cell,
     fmt::Display,
-    fs::File,
-    io::{BufReader, Read},
-    path::Path,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
 };
 
 use async_gcode::{Error, Literal, Parser, RealValue};
 use futures::stream;
 use futures_executor::block_on;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
 
-use crate::position::PositionMM;
+use crate::{
+    dxf::read_dxf, hpgl::read_hpgl, physical::Physical, position::PositionMM, svg::read_svg,
+};
 
 struct AxisTransformer {
     scale: f64,
@@ -98,6 +103,10 @@ impl AxisLimit {
         AxisLimit { val }
     }
 
+    pub fn get(&self) -> &[f64; 2] {
+        &self.val
+    }
+
     pub fn is_close_to(&self, other: &AxisLimit) -> bool {
         is_close::default().all_close(self.val, other.val)
     }
@@ -166,22 +175,121 @@ impl Display for AxisLimit {
     }
 }
 
-#[derive(Debug, PartialEq)]
+/// Paper limits "x0,x1,y0,y1" in mm
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PaperLimits([f64; 4]);
+
+impl PaperLimits {
+    pub fn axis_limits(&self) -> [AxisLimit; 2] {
+        let [x0, x1, y0, y1] = self.0;
+        [AxisLimit::new([x0, x1]), AxisLimit::new([y0, y1])]
+    }
+}
+
+impl FromStr for PaperLimits {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values: Vec<f64> = s
+            .split(',')
+            .map(|v| v.trim().parse::<f64>())
+            .collect::<Result<_, _>>()
+            .map_err(|_| "Failed to parse")?;
+        match values[..] {
+            [x0, x1, y0, y1] => Ok(PaperLimits([x0, x1, y0, y1])),
+            _ => Err("Did not get expected format"),
+        }
+    }
+}
+
+impl Display for PaperLimits {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let [x0, x1, y0, y1] = self.0;
+        write!(f, "{x0},{x1},{y0},{y1}")
+    }
+}
+
+/// Max distance in mm between a gcode arc and the chords that replace it
+pub const DEFAULT_ARC_TOLERANCE: f64 = 0.05;
+
+/// How drawing files are turned into programs
+#[derive(Clone, Debug)]
+pub struct ReadOptions {
+    /// Max distance in mm between an arc or curve and the chords that replace it
+    tolerance: f64,
+    /// dxf layers to draw, every layer if empty
+    layers: Vec<String>,
+    /// Draw each dxf layer in turn, pausing for a pen change between them
+    layer_passes: bool,
+}
+
+impl ReadOptions {
+    pub fn new(tolerance: f64, layers: Vec<String>, layer_passes: bool) -> Self {
+        ReadOptions {
+            tolerance,
+            layers,
+            layer_passes,
+        }
+    }
+    pub fn get_tolerance(&self) -> &f64 {
+        &self.tolerance
+    }
+    pub fn get_layers(&self) -> &Vec<String> {
+        &self.layers
+    }
+    pub fn get_layer_passes(&self) -> &bool {
+        &self.layer_passes
+    }
+}
+
+impl Default for ReadOptions {
+    fn default() -> Self {
+        ReadOptions::new(DEFAULT_ARC_TOLERANCE, Vec::new(), false)
+    }
+}
+
+const MM_PER_INCH: f64 = 25.4;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
 enum GCommand {
     FastMove,
     Move,
+    UseInches,
     UseMM,
     AbsoluteDistance,
+    RelativeDistance,
     AutoHoming,
+    ClockwiseArc,
+    CounterClockwiseArc,
+    XYPlane,
+    SetOffset,
+    ClearOffset,
+}
+
+impl GCommand {
+    fn is_motion(&self) -> bool {
+        matches!(
+            self,
+            GCommand::FastMove
+                | GCommand::Move
+                | GCommand::ClockwiseArc
+                | GCommand::CounterClockwiseArc
+        )
+    }
 }
 
 #[derive(Debug, Default, PartialEq)]
 struct GCode {
-    command: Option<GCommand>,
+    commands: Vec<GCommand>,
     x: Option<f64>,
     y: Option<f64>,
     z: Option<f64>,
+    i: Option<f64>,
+    j: Option<f64>,
+    r: Option<f64>,
     f: Option<f64>,
+    /// M0, a stop for the operator
+    stop: bool,
     comment: Option<String>,
 }
 
@@ -189,17 +297,29 @@ impl GCode {
     fn new() -> Self {
         Self::default()
     }
-    fn with_g(&mut self, val: f64) {
-        self.command = match val {
-            0.0 => Some(GCommand::FastMove),
-            1.0 => Some(GCommand::Move),
-            21.0 => Some(GCommand::UseMM),
-            90.0 => Some(GCommand::AbsoluteDistance),
-            28.0 => Some(GCommand::AutoHoming),
-            _ => {
-                panic!("got {val}");
-            }
+    fn with_g(&mut self, val: f64) -> Result<(), ()> {
+        // match on tenths so G92.1 is exact, refusing anything else rather than rounding it
+        let tenths = val * 10.0;
+        if val < 0.0 || (tenths - tenths.round()).abs() > 1e-6 {
+            return Err(());
+        }
+        let command = match tenths.round() as u32 {
+            0 => GCommand::FastMove,
+            10 => GCommand::Move,
+            20 => GCommand::ClockwiseArc,
+            30 => GCommand::CounterClockwiseArc,
+            170 => GCommand::XYPlane,
+            200 => GCommand::UseInches,
+            210 => GCommand::UseMM,
+            280 => GCommand::AutoHoming,
+            900 => GCommand::AbsoluteDistance,
+            910 => GCommand::RelativeDistance,
+            920 => GCommand::SetOffset,
+            921 => GCommand::ClearOffset,
+            _ => return Err(()),
         };
+        self.commands.push(command);
+        Ok(())
     }
     fn with_x(&mut self, val: f64) {
         self.x = Some(val);
@@ -210,37 +330,158 @@ impl GCode {
     fn with_z(&mut self, val: f64) {
         self.z = Some(val);
     }
+    fn with_i(&mut self, val: f64) {
+        self.i = Some(val);
+    }
+    fn with_j(&mut self, val: f64) {
+        self.j = Some(val);
+    }
+    fn with_r(&mut self, val: f64) {
+        self.r = Some(val);
+    }
     fn with_f(&mut self, val: f64) {
         self.f = Some(val);
     }
+    fn with_stop(&mut self) {
+        self.stop = true;
+    }
     fn with_comment(&mut self, val: String) {
         self.comment = Some(val);
     }
+    fn axes(&self) -> [Option<f64>; 3] {
+        [self.x, self.y, self.z]
+    }
+}
+
+/// Find the center of an R form arc. A negative radius selects the arc longer than 180 degrees.
+fn arc_center_from_radius(
+    start: &PositionMM,
+    end: &PositionMM,
+    radius: &f64,
+    clockwise: bool,
+) -> Result<PositionMM, &'static str> {
+    let chord = start.dist(end);
+    if chord == 0.0 {
+        return Err("Arc in R form needs distinct end points");
+    }
+    let half_chord = chord / 2.0;
+    if radius.abs() < half_chord && !is_close::default().is_close(radius.abs(), half_chord) {
+        return Err("Arc radius too small to reach end point");
+    }
+    let height = (radius.powi(2) - half_chord.powi(2)).max(0.0).sqrt();
+    let dir = start.get_direction(end);
+    // normal pointing to the left of the direction of travel
+    let left = [-dir[1], dir[0]];
+    // the center of a short counter clockwise arc is to the left
+    let mut height = if clockwise { -height } else { height };
+    if *radius < 0.0 {
+        height = -height;
+    }
+    let middle = start.offset(&half_chord, &dir);
+    Ok(middle.offset(&height, &left))
 }
 
+/// Chord end points of an arc, including every point where the arc crosses an axis extreme
+pub fn arc_to_chords(
+    start: &PositionMM,
+    end: &PositionMM,
+    center: &PositionMM,
+    clockwise: bool,
+    tolerance: &f64,
+) -> Vec<PositionMM> {
+    use std::f64::consts::{FRAC_PI_2, PI, TAU};
+    let angle = |mm: &PositionMM| (mm.y() - center.y()).atan2(mm.x() - center.x());
+    let a_start = angle(start);
+    let a_end = angle(end);
+    let r_start = start.dist(center);
+    let r_end = end.dist(center);
+    // rotation direction, +1 counter clockwise
+    let sign = if clockwise { -1.0 } else { 1.0 };
+    // matching start and end means a full circle
+    let sweep = (sign * (a_end - a_start)).rem_euclid(TAU);
+    let sweep = if sweep == 0.0 { TAU } else { sweep };
+    // largest angle whose chord stays within tolerance of the arc
+    let r_max = r_start.max(r_end);
+    let max_step = if *tolerance < r_max {
+        2.0 * (1.0 - tolerance / r_max).acos()
+    } else {
+        PI
+    };
+    let n = (sweep / max_step).ceil().max(1.0) as usize;
+    let mut offsets: Vec<f64> = (1..n).map(|k| sweep * k as f64 / n as f64).collect();
+    // keep the arc extremes exact so limits are right
+    for quadrant in 0..4 {
+        let offset = (sign * (quadrant as f64 * FRAC_PI_2 - a_start)).rem_euclid(TAU);
+        if offset > 0.0 && offset < sweep {
+            offsets.push(offset);
+        }
+    }
+    offsets.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    offsets.dedup_by(|a, b| (*a - *b).abs() < 1e-9);
+    let mut positions: Vec<PositionMM> = offsets
+        .into_iter()
+        .map(|offset| {
+            // blend the radius in case start and end disagree slightly
+            let r = r_start + (r_end - r_start) * offset / sweep;
+            let a = a_start + sign * offset;
+            PositionMM::new([center.x() + r * a.cos(), center.y() + r * a.sin()])
+        })
+        .collect();
+    positions.push(*end);
+    positions
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Axis {
     X,
     Y,
 }
 
+/// A change made to a program after it was read, kept so it can be made again on reload
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ProgramTransform {
+    ScaleAxis { axis: Axis, limit: [f64; 2] },
+    ScaleKeepAspect { x: [f64; 2], y: [f64; 2] },
+    CenterKeepAspect { x: [f64; 2], y: [f64; 2] },
+}
+
+/// How fast a move should be made
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Feed {
+    /// G0 travel at the rapid velocity
+    Rapid,
+    /// Feed rate in mm/s
+    Rate(f64),
+    /// As fast as the machine allows
+    Max,
+}
+
 #[derive(Clone)]
 pub enum PlotterInstruction {
-    Move(PositionMM),
+    Move {
+        target: PositionMM,
+        feed: Feed,
+    },
     PenUp,
     PenDown,
+    /// Find the machine position with the endstops
+    Home,
+    /// Lift the pen and wait for the operator to fit the pen described
+    ChangePen(String),
     Comment(String),
-    NoOp,
 }
 
 impl PlotterInstruction {
     fn update_limits(&self, x_limits: &mut MaybeAxisLimit, y_limits: &mut MaybeAxisLimit) {
-        if let PlotterInstruction::Move(pos) = self {
+        if let PlotterInstruction::Move { target: pos, .. } = self {
             x_limits.update(pos.x());
             y_limits.update(pos.y());
         }
     }
     fn transform(&mut self, transform: &AxisTransformer, axis: &Axis) {
-        if let PlotterInstruction::Move(pos) = self {
+        if let PlotterInstruction::Move { target: pos, .. } = self {
             let inner_val: &mut f64 = match axis {
                 Axis::X => pos.x_mut(),
                 Axis::Y => pos.y_mut(),
@@ -249,7 +490,7 @@ impl PlotterInstruction {
         }
     }
     fn transpose(&mut self, transposer: &AxisTransposer, axis: &Axis) {
-        if let PlotterInstruction::Move(pos) = self {
+        if let PlotterInstruction::Move { target: pos, .. } = self {
             let inner_val: &mut f64 = match axis {
                 Axis::X => pos.x_mut(),
                 Axis::Y => pos.y_mut(),
@@ -259,54 +500,565 @@ impl PlotterInstruction {
     }
 }
 
-impl TryFrom<GCode> for PlotterInstruction {
-    type Error = &'static str;
+/// A problem interpreting a block, with the letter of the word it is about
+type WordProblem = (char, &'static str);
 
-    fn try_from(value: GCode) -> Result<Self, Self::Error> {
-        match value.command {
-            Some(command) => match command {
-                GCommand::Move | GCommand::FastMove => match value.z {
-                    Some(z_val) => {
-                        if value.x.is_some() | value.y.is_some() {
-                            Err("Did not expect a 3D move")
-                        } else if z_val < 0.0 {
-                            Err("Did not expect negative z value")
-                        } else if z_val == 0.0 {
-                            Ok(PlotterInstruction::PenDown)
-                        } else {
-                            Ok(PlotterInstruction::PenUp)
-                        }
+/// Modal state carried from block to block while interpreting gcode
+struct ModalState {
+    /// mm per program unit
+    units: f64,
+    relative: bool,
+    /// G92 offset in mm from program coordinates to absolute coordinates
+    offset: [f64; 3],
+    /// Absolute x, y and z in mm, if known
+    position: [Option<f64>; 3],
+    motion: Option<GCommand>,
+    /// Feed rate in mm/s, if one has been given
+    feed: Option<f64>,
+    /// Problems with the last block that did not stop it being used
+    warnings: Vec<WordProblem>,
+}
+
+impl Default for ModalState {
+    fn default() -> Self {
+        ModalState {
+            units: 1.0,
+            relative: false,
+            offset: [0.0; 3],
+            position: [None; 3],
+            motion: None,
+            feed: None,
+            warnings: Vec::new(),
+        }
+    }
+}
+
+impl ModalState {
+    fn new() -> Self {
+        Self::default()
+    }
+    /// Current position of an axis. A program that has not said where it is starts at the
+    /// origin, so relative programs keep their shape.
+    fn current(&self, axis: usize) -> f64 {
+        self.position[axis].unwrap_or(0.0)
+    }
+    /// Absolute position in mm of each axis after applying the block's axis words
+    fn target(&self, code: &GCode) -> [Option<f64>; 3] {
+        let mut target = self.position;
+        for (axis, val) in code.axes().iter().enumerate() {
+            if let Some(val) = val {
+                let val = val * self.units;
+                target[axis] = Some(if self.relative {
+                    self.current(axis) + val
+                } else {
+                    val + self.offset[axis]
+                });
+            }
+        }
+        target
+    }
+    /// G92: make the current position read as the given program coordinates
+    fn set_offset(&mut self, code: &GCode) {
+        for (axis, val) in code.axes().iter().enumerate() {
+            if let Some(val) = val {
+                self.offset[axis] = self.current(axis) - val * self.units;
+            }
+        }
+    }
+    /// Speed of a move made with the given motion command
+    fn feed(&self, motion: GCommand) -> Feed {
+        match (motion, self.feed) {
+            (GCommand::FastMove, _) => Feed::Rapid,
+            (_, Some(rate)) => Feed::Rate(rate),
+            (_, None) => Feed::Max,
+        }
+    }
+    fn interpret(
+        &mut self,
+        code: GCode,
+        arc_tolerance: &f64,
+    ) -> Result<Vec<PlotterInstruction>, WordProblem> {
+        // M0 waits for the operator once the rest of the block is done, its comment saying what
+        // pen to fit
+        let stop = code.stop.then(|| {
+            PlotterInstruction::ChangePen(
+                code.comment
+                    .clone()
+                    .unwrap_or_else(|| String::from("the next pen")),
+            )
+        });
+        let mut instructions = self.interpret_words(code, arc_tolerance)?;
+        instructions.extend(stop);
+        Ok(instructions)
+    }
+    fn interpret_words(
+        &mut self,
+        code: GCode,
+        arc_tolerance: &f64,
+    ) -> Result<Vec<PlotterInstruction>, WordProblem> {
+        let mut instructions = Vec::new();
+        let comment = |c: &str| PlotterInstruction::Comment(String::from(c));
+        if let Some(val) = code.comment.as_ref().filter(|_| !code.stop) {
+            instructions.push(PlotterInstruction::Comment(val.clone()));
+        }
+        if let Some(f) = code.f {
+            if f <= 0.0 {
+                return Err(('f', "Feed rate must be positive"));
+            }
+            // F is in program units per minute
+            self.feed = Some(f * self.units / 60.0);
+        }
+        let mut motion = None;
+        // axis words that belong to a non motion command
+        let mut axes_consumed = false;
+        for command in &code.commands {
+            match command {
+                GCommand::UseMM => {
+                    self.units = 1.0;
+                    instructions.push(comment("Use mm"));
+                }
+                GCommand::UseInches => {
+                    self.units = MM_PER_INCH;
+                    instructions.push(comment("Use inches"));
+                }
+                GCommand::AbsoluteDistance => {
+                    self.relative = false;
+                    instructions.push(comment("Absolute distance"));
+                }
+                GCommand::RelativeDistance => {
+                    self.relative = true;
+                    instructions.push(comment("Relative distance"));
+                }
+                GCommand::AutoHoming => {
+                    // axis words name an intermediate point, which homing does not need
+                    axes_consumed = true;
+                    // the home position is in machine coordinates
+                    self.position[0] = None;
+                    self.position[1] = None;
+                    instructions.push(PlotterInstruction::Home);
+                }
+                GCommand::XYPlane => instructions.push(comment("XY plane")),
+                GCommand::SetOffset => {
+                    axes_consumed = true;
+                    self.set_offset(&code);
+                    instructions.push(comment("Set position offset"));
+                }
+                GCommand::ClearOffset => {
+                    self.offset = [0.0; 3];
+                    instructions.push(comment("Clear position offset"));
+                }
+                GCommand::FastMove
+                | GCommand::Move
+                | GCommand::ClockwiseArc
+                | GCommand::CounterClockwiseArc => {
+                    motion = Some(*command);
+                    self.motion = Some(*command);
+                }
+            }
+        }
+        let has_axes = code.axes().iter().any(Option::is_some);
+        if axes_consumed {
+            return Ok(instructions);
+        }
+        if !has_axes {
+            return Ok(instructions);
+        }
+        let motion = match motion.or(self.motion) {
+            Some(motion) => motion,
+            None => {
+                let axis = ['x', 'y', 'z']
+                    .into_iter()
+                    .zip(code.axes())
+                    .find_map(|(letter, val)| val.map(|_| letter))
+                    .unwrap_or('x');
+                return Err((axis, "Axis words without a motion command"));
+            }
+        };
+        debug_assert!(motion.is_motion());
+        match motion {
+            GCommand::ClockwiseArc | GCommand::CounterClockwiseArc => {
+                let feed = self.feed(motion);
+                let arc = self.arc_positions(&code, motion, arc_tolerance)?;
+                instructions.extend(
+                    arc.into_iter()
+                        .map(|target| PlotterInstruction::Move { target, feed }),
+                );
+            }
+            _ => instructions.push(self.linear_move(&code, motion)?),
+        }
+        Ok(instructions)
+    }
+    fn linear_move(
+        &mut self,
+        code: &GCode,
+        motion: GCommand,
+    ) -> Result<PlotterInstruction, WordProblem> {
+        let target = self.target(code);
+        if code.z.is_some() {
+            if code.x.is_some() | code.y.is_some() {
+                return Err(('z', "Did not expect a 3D move"));
+            }
+            let z_val = target[2].unwrap();
+            self.position[2] = Some(z_val);
+            return if z_val < 0.0 {
+                Err(('z', "Did not expect negative z value"))
+            } else if z_val == 0.0 {
+                Ok(PlotterInstruction::PenDown)
+            } else {
+                Ok(PlotterInstruction::PenUp)
+            };
+        }
+        match target {
+            [None, _, _] => Err(('y', "Move missing X")),
+            [_, None, _] => Err(('x', "Move missing Y")),
+            [Some(x), Some(y), _] => {
+                self.position[0] = Some(x);
+                self.position[1] = Some(y);
+                Ok(PlotterInstruction::Move {
+                    target: PositionMM::new([x, y]),
+                    feed: self.feed(motion),
+                })
+            }
+        }
+    }
+    /// Break a G2/G3 arc into chords that stray at most `tolerance` mm from the arc
+    ///
+    /// Helical arcs are projected on to the XY plane.
+    fn arc_positions(
+        &mut self,
+        code: &GCode,
+        motion: GCommand,
+        tolerance: &f64,
+    ) -> Result<Vec<PositionMM>, WordProblem> {
+        let clockwise = motion == GCommand::ClockwiseArc;
+        let (Some(x), Some(y)) = (self.position[0], self.position[1]) else {
+            return Err(('g', "Arc needs a start position"));
+        };
+        let start = PositionMM::new([x, y]);
+        if code.z.is_some() {
+            self.warnings.push(('z', "ignoring Z of helical arc"));
+        }
+        let target = self.target(code);
+        let end = PositionMM::new([target[0].unwrap(), target[1].unwrap()]);
+        // I, J and R are always incremental
+        let center = match (code.i, code.j, code.r) {
+            (None, None, Some(r)) => {
+                arc_center_from_radius(&start, &end, &(r * self.units), clockwise)
+                    .map_err(|reason| ('r', reason))?
+            }
+            (None, None, None) => return Err(('g', "Arc missing I/J or R")),
+            (_, _, Some(_)) => return Err(('r', "Arc has both I/J and R")),
+            (i, j, None) => PositionMM::new([
+                start.x() + i.unwrap_or(0.0) * self.units,
+                start.y() + j.unwrap_or(0.0) * self.units,
+            ]),
+        };
+        if start.dist(&center) == 0.0 {
+            let offset = if code.i.is_some() { 'i' } else { 'j' };
+            return Err((offset, "Arc has zero radius"));
+        }
+        self.position[0] = Some(*end.x());
+        self.position[1] = Some(*end.y());
+        Ok(arc_to_chords(&start, &end, &center, clockwise, tolerance))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// A problem found at a place in a gcode file
+#[derive(Debug)]
+pub struct Diagnostic {
+    severity: Severity,
+    /// 1 based line number, 0 for problems with the file as a whole
+    line: usize,
+    /// 1 based column
+    column: usize,
+    block: String,
+    reason: String,
+}
+
+impl Diagnostic {
+    fn file(reason: String) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            line: 0,
+            column: 0,
+            block: String::new(),
+            reason,
+        }
+    }
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.line == 0 {
+            write!(f, "{}: {}", self.severity, self.reason)
+        } else {
+            write!(
+                f,
+                "{}: line {}, column {}: {}: \"{}\"",
+                self.severity, self.line, self.column, self.reason, self.block
+            )
+        }
+    }
+}
+
+/// Every problem found in a gcode file that could not be loaded
+#[derive(Debug)]
+pub struct GCodeError {
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Display for GCodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for diagnostic in &self.diagnostics {
+            writeln!(f, "{diagnostic}")?;
+        }
+        let errors = self.diagnostics.iter().filter(|d| d.is_error()).count();
+        write!(f, "{errors} error(s) reading gcode")
+    }
+}
+
+impl std::error::Error for GCodeError {}
+
+/// Parse one line of gcode, noting anything that can't be used
+///
+/// A block starting with `/` is skipped, as with the block delete switch on.
+fn parse_block(line_number: usize, line: &[u8]) -> (GCode, Vec<Diagnostic>) {
+    let block = String::from_utf8_lossy(line).to_string();
+    let mut gcode = GCode::new();
+    let mut diagnostics = Vec::new();
+    let mut deleted = false;
+    let mut diagnose = |severity, column, reason| {
+        diagnostics.push(Diagnostic {
+            severity,
+            line: line_number,
+            column,
+            block: block.clone(),
+            reason,
+        })
+    };
+    // bytes handed to the parser so far
+    let consumed = Cell::new(0);
+    // the parser may have read a byte past the word, so look back for its letter
+    let word_column = |letter: char| {
+        let read = &line[..consumed.get().min(line.len())];
+        read.iter()
+            .rposition(|b| b.eq_ignore_ascii_case(&(letter as u8)))
+            .map_or(1, |i| i + 1)
+    };
+    block_on(async {
+        let input = stream::iter(line.iter().chain(b"\n").map(|b| {
+            consumed.set(consumed.get() + 1);
+            Result::<_, Error>::Ok(*b)
+        }));
+        let mut parser = Parser::new(input);
+        while let Some(res) = parser.next().await {
+            match res {
+                Ok(gc) => match gc {
+                    async_gcode::GCode::BlockDelete => {
+                        deleted = true;
                     }
-                    None => {
-                        if value.f.is_some() && value.x.is_none() && value.y.is_none() {
-                            let feed = value.f.unwrap();
-                            Ok(PlotterInstruction::Comment(format!("feed {feed}")))
-                        } else if value.x.is_none() {
-                            Err("Move missing X")
-                        } else if value.y.is_none() {
-                            Err("Move missing Y")
-                        } else {
-                            Ok(PlotterInstruction::Move(PositionMM::new([
-                                value.x.unwrap(),
-                                value.y.unwrap(),
-                            ])))
+                    async_gcode::GCode::LineNumber(_) | async_gcode::GCode::Execute => {}
+                    async_gcode::GCode::Word(c, RealValue::Literal(Literal::RealNumber(v))) => {
+                        let word = format!("{}{v}", c.to_ascii_uppercase());
+                        match c {
+                            'g' => {
+                                if gcode.with_g(v).is_err() {
+                                    diagnose(
+                                        Severity::Error,
+                                        word_column(c),
+                                        format!("unsupported {word}"),
+                                    );
+                                }
+                            }
+                            'x' => {
+                                gcode.with_x(v);
+                            }
+                            'y' => {
+                                gcode.with_y(v);
+                            }
+                            'z' => {
+                                gcode.with_z(v);
+                            }
+                            'i' => {
+                                gcode.with_i(v);
+                            }
+                            'j' => {
+                                gcode.with_j(v);
+                            }
+                            'r' => {
+                                gcode.with_r(v);
+                            }
+                            'f' => {
+                                gcode.with_f(v);
+                            }
+                            'm' if v == 0.0 => {
+                                gcode.with_stop();
+                            }
+                            'm' | 's' | 't' | 'p' => {
+                                diagnose(
+                                    Severity::Warning,
+                                    word_column(c),
+                                    format!("ignoring {word}"),
+                                );
+                            }
+                            _ => {
+                                diagnose(
+                                    Severity::Error,
+                                    word_column(c),
+                                    format!("unsupported {word}"),
+                                );
+                            }
                         }
                     }
+                    async_gcode::GCode::Comment(msg) => {
+                        gcode.with_comment(msg);
+                    }
                 },
-                GCommand::UseMM => Ok(PlotterInstruction::Comment(String::from("Use mm"))),
-                GCommand::AbsoluteDistance => Ok(PlotterInstruction::Comment(String::from(
-                    "Absolute distance",
-                ))),
-                GCommand::AutoHoming => {
-                    Ok(PlotterInstruction::Comment(String::from("Auto homing")))
+                Err(e) => {
+                    diagnose(Severity::Error, consumed.get(), format!("syntax: {e:?}"));
                 }
-            },
-            None => match value.comment {
-                Some(val) => Ok(PlotterInstruction::Comment(val)),
-                None => Ok(PlotterInstruction::NoOp),
-            },
+            }
+        }
+    });
+    if deleted {
+        let skipped = Diagnostic {
+            severity: Severity::Warning,
+            line: line_number,
+            column: find_word(line, '/'),
+            block,
+            reason: String::from("skipping deleted block"),
+        };
+        return (GCode::default(), vec![skipped]);
+    }
+    (gcode, diagnostics)
+}
+
+/// 1 based column of the first word with the given letter outside of comments, or 1 if the
+/// block has none, as when the word is modal
+fn find_word(line: &[u8], letter: char) -> usize {
+    let mut in_comment = false;
+    for (i, b) in line.iter().enumerate() {
+        match b {
+            b'(' => in_comment = true,
+            b')' => in_comment = false,
+            b';' if !in_comment => break,
+            _ if !in_comment && b.eq_ignore_ascii_case(&(letter as u8)) => return i + 1,
+            _ => {}
         }
     }
+    1
+}
+
+/// Interpret every line of a gcode file, collecting all problems in one pass
+fn read_gcode(path: &Path, arc_tolerance: &f64) -> (Vec<PlotterInstruction>, Vec<Diagnostic>) {
+    let buf = match fs::read(path) {
+        Ok(buf) => buf,
+        Err(e) => {
+            let reason = format!("failed to read {}: {e}", path.display());
+            return (Vec::new(), vec![Diagnostic::file(reason)]);
+        }
+    };
+    let mut instructions = Vec::new();
+    let mut diagnostics = Vec::new();
+    let mut state = ModalState::new();
+    for (index, line) in buf.split(|b| *b == b'\n').enumerate() {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let (code, mut block_diagnostics) = parse_block(index + 1, line);
+        let parsed = !block_diagnostics.iter().any(Diagnostic::is_error);
+        if parsed && code != GCode::default() {
+            let mut diagnose = |severity, (letter, reason): WordProblem| {
+                block_diagnostics.push(Diagnostic {
+                    severity,
+                    line: index + 1,
+                    column: find_word(line, letter),
+                    block: String::from_utf8_lossy(line).to_string(),
+                    reason: String::from(reason),
+                })
+            };
+            match state.interpret(code, arc_tolerance) {
+                Ok(block_instructions) => instructions.extend(block_instructions),
+                Err(problem) => diagnose(Severity::Error, problem),
+            }
+            for warning in state.warnings.drain(..) {
+                diagnose(Severity::Warning, warning);
+            }
+        }
+        diagnostics.append(&mut block_diagnostics);
+    }
+    (instructions, diagnostics)
+}
+
+/// Leading moves of `instructions`, up to `count` of them or the first pen change
+pub fn upcoming_moves(
+    instructions: &[PlotterInstruction],
+    count: &usize,
+) -> Vec<(PositionMM, Feed)> {
+    instructions
+        .iter()
+        .filter(|instruction| !matches!(instruction, PlotterInstruction::Comment(_)))
+        .map_while(|instruction| match instruction {
+            PlotterInstruction::Move { target, feed } => Some((*target, *feed)),
+            _ => None,
+        })
+        .take(*count)
+        .collect()
+}
+
+/// Interprets gcode a block at a time, for senders that stream a program line by line
+pub struct GCodeStream {
+    state: ModalState,
+    arc_tolerance: f64,
+    line: usize,
+}
+
+impl GCodeStream {
+    pub fn new(arc_tolerance: f64) -> Self {
+        GCodeStream {
+            state: ModalState::new(),
+            arc_tolerance,
+            line: 0,
+        }
+    }
+    /// Take the pen to be at `mm`, which blocks that leave out an axis or move relative to
+    /// where it is start from
+    pub fn set_position(&mut self, mm: &PositionMM) {
+        self.state.position[0] = Some(*mm.x());
+        self.state.position[1] = Some(*mm.y());
+    }
+    /// Instructions for one block, or the reason it can not be used
+    pub fn block(&mut self, line: &str) -> Result<Vec<PlotterInstruction>, String> {
+        self.line += 1;
+        let (code, diagnostics) = parse_block(self.line, line.as_bytes());
+        if let Some(diagnostic) = diagnostics.into_iter().find(Diagnostic::is_error) {
+            return Err(diagnostic.reason);
+        }
+        if code == GCode::default() {
+            return Ok(Vec::new());
+        }
+        let instructions = self.state.interpret(code, &self.arc_tolerance);
+        for (_, warning) in self.state.warnings.drain(..) {
+            warn!("line {}: {warning}", self.line);
+        }
+        instructions.map_err(|(_, reason)| String::from(reason))
+    }
 }
 
 pub struct PlotterProgram {
@@ -316,6 +1068,10 @@ pub struct PlotterProgram {
     y_limits: AxisLimit,
     current_position: usize,
     next_lift: Option<usize>,
+    /// gcode file the program was read from
+    source: Option<PathBuf>,
+    /// Scaling and centering applied since it was read, in order
+    transforms: Vec<ProgramTransform>,
 }
 
 impl Iterator for PlotterProgram {
@@ -338,13 +1094,18 @@ impl Iterator for PlotterProgram {
 }
 
 impl PlotterProgram {
-    fn compute_time_remaining(instructions: &[PlotterInstruction], max_velocity: &f64) -> Vec<f64> {
+    /// Moves are timed at the velocity they coast at, ignoring acceleration
+    fn compute_time_remaining(
+        instructions: &[PlotterInstruction],
+        physical: &Physical,
+    ) -> Vec<f64> {
         let mut pos = None;
         let mut time_per_instruction: Vec<f64> = Vec::with_capacity(instructions.len());
         for ins in instructions {
-            let time = if let PlotterInstruction::Move(ipos) = ins {
+            let time = if let PlotterInstruction::Move { target: ipos, feed } = ins {
+                let velocity = physical.feed_velocity(feed);
                 let time = if let Some(last) = pos {
-                    ipos.dist(last) / max_velocity
+                    ipos.dist(last) / velocity
                 } else {
                     0.0
                 };
@@ -390,10 +1151,10 @@ impl PlotterProgram {
     }
     pub fn new(
         instructions: Vec<PlotterInstruction>,
-        max_velocity: &f64,
+        physical: &Physical,
     ) -> Result<Self, &'static str> {
         let [x_limits, y_limits] = PlotterProgram::compute_limits(&instructions)?;
-        let time_remaining = PlotterProgram::compute_time_remaining(&instructions, max_velocity);
+        let time_remaining = PlotterProgram::compute_time_remaining(&instructions, physical);
         let next_lift = PlotterProgram::find_next_lift(&instructions, &0);
         Ok(PlotterProgram {
             instructions,
@@ -402,6 +1163,8 @@ impl PlotterProgram {
             y_limits,
             current_position: 0,
             next_lift,
+            source: None,
+            transforms: Vec::new(),
         })
     }
     pub fn time_remaining(&self) -> &f64 {
@@ -422,22 +1185,32 @@ impl PlotterProgram {
     pub fn from_positions(
         start: &PositionMM,
         positions: Vec<PositionMM>,
-        max_velocity: &f64,
+        physical: &Physical,
     ) -> Result<PlotterProgram, &'static str> {
         let mut instructions = vec![
             PlotterInstruction::PenUp,
-            PlotterInstruction::Move(*start),
+            PlotterInstruction::Move {
+                target: *start,
+                feed: Feed::Rapid,
+            },
             PlotterInstruction::PenDown,
         ];
         let mut positions: Vec<PlotterInstruction> = positions
             .into_iter()
-            .map(PlotterInstruction::Move)
+            .map(|target| PlotterInstruction::Move {
+                target,
+                feed: Feed::Max,
+            })
             .collect();
         instructions.append(&mut positions);
         instructions.push(PlotterInstruction::PenUp);
-        PlotterProgram::new(instructions, max_velocity)
+        PlotterProgram::new(instructions, physical)
     }
 
+    /// Moves after the current position, up to `count` of them or the next pen change
+    pub fn upcoming_moves(&self, count: &usize) -> Vec<(PositionMM, Feed)> {
+        upcoming_moves(&self.instructions[self.current_position..], count)
+    }
     pub fn within_limits(&self, limits: &[AxisLimit; 2]) -> bool {
         self.x_limits.is_inside_of(&limits[0]) && self.y_limits.is_inside_of(&limits[1])
     }
@@ -445,12 +1218,73 @@ impl PlotterProgram {
         self.current_position = 0;
         self.next_lift = PlotterProgram::find_next_lift(&self.instructions, &self.current_position);
     }
+    /// Carry on from instruction `index` on the next call to `next`
+    pub fn seek(&mut self, index: &usize) -> Result<(), &'static str> {
+        if *index > self.instructions.len() {
+            return Err("Instruction index past the end of the program");
+        }
+        self.current_position = *index;
+        self.next_lift = PlotterProgram::find_next_lift(&self.instructions, &self.current_position);
+        Ok(())
+    }
+    /// Index of the last pen lift at or before `index`, or the start of the program
+    pub fn last_lift_before(&self, index: &usize) -> usize {
+        let end = (index + 1).min(self.instructions.len());
+        self.instructions[..end]
+            .iter()
+            .rposition(|instruction| matches!(instruction, PlotterInstruction::PenUp))
+            .unwrap_or(0)
+    }
+    /// Target of the last move and whether the pen is down before the current instruction
+    pub fn state_before_current(&self) -> (Option<PositionMM>, bool) {
+        let before = &self.instructions[..self.current_position];
+        let target = before
+            .iter()
+            .rev()
+            .find_map(|instruction| match instruction {
+                PlotterInstruction::Move { target, .. } => Some(*target),
+                _ => None,
+            });
+        let pen_down = before
+            .iter()
+            .rev()
+            .find_map(|instruction| match instruction {
+                PlotterInstruction::PenUp | PlotterInstruction::ChangePen(_) => Some(false),
+                PlotterInstruction::PenDown => Some(true),
+                _ => None,
+            })
+            .unwrap_or(false);
+        (target, pen_down)
+    }
     pub fn len(&self) -> usize {
         self.instructions.len()
     }
     pub fn current_position(&self) -> usize {
         self.current_position
     }
+    pub fn get_source(&self) -> &Option<PathBuf> {
+        &self.source
+    }
+    pub fn get_instructions(&self) -> &[PlotterInstruction] {
+        &self.instructions
+    }
+    pub fn get_transforms(&self) -> &[ProgramTransform] {
+        &self.transforms
+    }
+    /// Make a recorded transform again
+    pub fn apply(&mut self, transform: &ProgramTransform) -> Result<(), &'static str> {
+        match transform {
+            ProgramTransform::ScaleAxis { axis, limit } => {
+                self.scale_axis(&AxisLimit::new(*limit), axis)
+            }
+            ProgramTransform::ScaleKeepAspect { x, y } => {
+                self.scale_keep_aspect(&AxisLimit::new(*x), &AxisLimit::new(*y))
+            }
+            ProgramTransform::CenterKeepAspect { x, y } => {
+                self.center_keep_aspect(&AxisLimit::new(*x), &AxisLimit::new(*y))
+            }
+        }
+    }
     fn get_limit(&self, axis: &Axis) -> &AxisLimit {
         match axis {
             Axis::X => &self.x_limits,
@@ -463,6 +1297,10 @@ impl PlotterProgram {
         for instruction in &mut self.instructions {
             instruction.transform(&transformer, axis);
         }
+        self.transforms.push(ProgramTransform::ScaleAxis {
+            axis: *axis,
+            limit: limit.val,
+        });
         self.update_limits()
             .expect("Limit calculation failed after scaling");
         let cur_limits = self.get_limit(axis);
@@ -484,6 +1322,10 @@ impl PlotterProgram {
             instruction.transpose(&x_transpose, &Axis::X);
             instruction.transpose(&y_transpose, &Axis::Y);
         }
+        self.transforms.push(ProgramTransform::CenterKeepAspect {
+            x: x_limit.val,
+            y: y_limit.val,
+        });
         self.update_limits()?;
         if !self.x_limits.is_inside_of(x_limit) {
             Err("No in X bounds")
@@ -515,6 +1357,10 @@ impl PlotterProgram {
             instruction.transform(&x_transform, &Axis::X);
             instruction.transform(&y_transform, &Axis::Y);
         }
+        self.transforms.push(ProgramTransform::ScaleKeepAspect {
+            x: x_limit.val,
+            y: y_limit.val,
+        });
         self.update_limits()?;
         if !self.x_limits.is_inside_of(x_limit) {
             Err("Not in x")
@@ -525,98 +1371,161 @@ impl PlotterProgram {
         }
     }
 
+    /// arc_tolerance: max distance in mm between an arc and the chords that replace it
     pub fn read_gcode_file(
         path: &Path,
-        max_velocity: &f64,
+        physical: &Physical,
+        arc_tolerance: &f64,
+    ) -> Result<PlotterProgram, GCodeError> {
+        let (instructions, diagnostics) = read_gcode(path, arc_tolerance);
+        if diagnostics.iter().any(Diagnostic::is_error) {
+            return Err(GCodeError { diagnostics });
+        }
+        for warning in &diagnostics {
+            log::warn!("{warning}");
+        }
+        let mut program =
+            PlotterProgram::new(instructions, physical).map_err(|reason| GCodeError {
+                diagnostics: vec![Diagnostic::file(String::from(reason))],
+            })?;
+        program.source = Some(path.to_owned());
+        Ok(program)
+    }
+    /// Read a program from an svg, hpgl or dxf file, or from gcode for any other extension
+    pub fn read_file(
+        path: &Path,
+        physical: &Physical,
+        options: &ReadOptions,
+    ) -> Result<PlotterProgram, Box<dyn std::error::Error>> {
+        let tolerance = options.get_tolerance();
+        let extension = path.extension().and_then(|e| e.to_str());
+        match extension.map(str::to_ascii_lowercase).as_deref() {
+            Some("svg") => Ok(PlotterProgram::read_svg_file(path, physical, tolerance)?),
+            Some("hpgl" | "hgl" | "plt") => Ok(PlotterProgram::read_hpgl_file(path, physical)?),
+            Some("dxf") => Ok(PlotterProgram::read_dxf_file(path, physical, options)?),
+            _ => Ok(PlotterProgram::read_gcode_file(path, physical, tolerance)?),
+        }
+    }
+    pub fn read_svg_file(
+        path: &Path,
+        physical: &Physical,
+        tolerance: &f64,
     ) -> Result<PlotterProgram, &'static str> {
-        let file = File::open(path).expect("failed to open file");
-        let mut reader = BufReader::new(file);
-        let mut buf = Vec::new();
-        reader.read_to_end(&mut buf).expect("failed to read");
-        let mut codes = Vec::new();
-        block_on(async {
-            let input = stream::iter(buf.into_iter().map(Result::<_, Error>::Ok));
-            let mut parser = Parser::new(input);
-            let mut gcode = GCode::new();
-            // keep a x/y state to fill out any potential move commands
-            // that only have one of these
-            let mut pos = [None; 2];
-            let mut pos_set = [false; 2];
-            while let Some(res) = parser.next().await {
-                match res {
-                    Ok(gc) => {
-                        match gc {
-                            async_gcode::GCode::BlockDelete => todo!(),
-                            async_gcode::GCode::LineNumber(_) => todo!(),
-                            async_gcode::GCode::Word(
-                                c,
-                                RealValue::Literal(Literal::RealNumber(v)),
-                            ) => match c {
-                                'g' => {
-                                    gcode.with_g(v);
-                                }
-                                'x' => {
-                                    pos[0] = Some(v);
-                                    pos_set[0] = true;
-                                    gcode.with_x(v);
-                                }
-                                'y' => {
-                                    pos[1] = Some(v);
-                                    pos_set[1] = true;
-                                    gcode.with_y(v);
-                                }
-                                'z' => {
-                                    gcode.with_z(v);
-                                }
-                                'f' => {
-                                    gcode.with_f(v);
-                                }
-                                _ => {
-                                    panic!("got {c}");
-                                }
-                            },
-                            async_gcode::GCode::Execute => {
-                                if gcode != GCode::default() {
-                                    if pos_set[0] && !pos_set[1] {
-                                        gcode.with_y(pos[1].expect(
-                                            "Expecting to not start with a single axis move",
-                                        ))
-                                    }
-                                    if pos_set[1] && !pos_set[0] {
-                                        gcode.with_x(pos[0].expect(
-                                            "Expecting to not start with a single axis move",
-                                        ))
-                                    }
-                                    codes.push(gcode);
-                                    gcode = GCode::new();
-                                    pos_set = [false; 2];
-                                }
-                            }
-                            async_gcode::GCode::Comment(msg) => {
-                                gcode.with_comment(msg);
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        log::error!("Got error: {e:?}");
-                        continue;
+        let mut program = PlotterProgram::new(read_svg(path, tolerance)?, physical)?;
+        program.source = Some(path.to_owned());
+        Ok(program)
+    }
+    pub fn read_hpgl_file(
+        path: &Path,
+        physical: &Physical,
+    ) -> Result<PlotterProgram, &'static str> {
+        let mut program = PlotterProgram::new(read_hpgl(path)?, physical)?;
+        program.source = Some(path.to_owned());
+        Ok(program)
+    }
+    pub fn read_dxf_file(
+        path: &Path,
+        physical: &Physical,
+        options: &ReadOptions,
+    ) -> Result<PlotterProgram, &'static str> {
+        let mut program = PlotterProgram::new(read_dxf(path, options)?, physical)?;
+        program.source = Some(path.to_owned());
+        Ok(program)
+    }
+    /// Read a gcode file and report every error and warning without stopping at the first
+    pub fn check_gcode_file(
+        path: &Path,
+        physical: &Physical,
+        arc_tolerance: &f64,
+    ) -> Vec<Diagnostic> {
+        let (instructions, mut diagnostics) = read_gcode(path, arc_tolerance);
+        if let Err(reason) = PlotterProgram::new(instructions, physical) {
+            diagnostics.push(Diagnostic::file(String::from(reason)));
+        }
+        diagnostics
+    }
+    /// The program as gcode that reads back to the same moves
+    ///
+    /// The pen is Z1 up and Z0 down, pen changes are M0 stops and comments are left out. With
+    /// feed rates every feed move gets an F word, taking moves made as fast as the machine
+    /// allows to be at `max_velocity`; without them feed moves run as fast as the machine
+    /// allows.
+    fn to_gcode(&self, feed_rates: &bool, max_velocity: &f64) -> String {
+        let mut lines = Vec::new();
+        if let Some(source) = &self.source {
+            lines.push(format!(
+                "(from {})",
+                comment_text(&source.display().to_string())
+            ));
+        }
+        lines.extend(["G21", "G90"].map(String::from));
+        // F of the last feed move, in mm/min
+        let mut last_rate = None;
+        for instruction in &self.instructions {
+            let line = match instruction {
+                PlotterInstruction::Move {
+                    target,
+                    feed: Feed::Rapid,
+                } => format!(
+                    "G0 X{} Y{}",
+                    gcode_number(target.x()),
+                    gcode_number(target.y())
+                ),
+                PlotterInstruction::Move { target, feed } => {
+                    let mut line = format!(
+                        "G1 X{} Y{}",
+                        gcode_number(target.x()),
+                        gcode_number(target.y())
+                    );
+                    let rate = match feed {
+                        Feed::Rate(rate) => rate,
+                        _ => max_velocity,
+                    } * 60.0;
+                    if *feed_rates && last_rate != Some(rate) {
+                        line.push_str(&format!(" F{}", gcode_number(&rate)));
+                        last_rate = Some(rate);
                     }
+                    line
                 }
-            }
-        });
-        let mut instructions = Vec::new();
-        for code in codes {
-            let instruction = PlotterInstruction::try_from(code)?;
-            if let PlotterInstruction::NoOp = instruction {
-                continue;
-            }
-            instructions.push(instruction)
+                PlotterInstruction::PenUp => String::from("G0 Z1"),
+                PlotterInstruction::PenDown => String::from("G0 Z0"),
+                PlotterInstruction::Home => String::from("G28"),
+                PlotterInstruction::ChangePen(pen) => format!("M0 ({})", comment_text(pen)),
+                PlotterInstruction::Comment(_) => continue,
+            };
+            lines.push(line);
         }
-        let program = PlotterProgram::new(instructions, max_velocity)?;
-        Ok(program)
+        lines.push(String::new());
+        lines.join("\n")
+    }
+    pub fn write_gcode_file(
+        &self,
+        path: &Path,
+        feed_rates: &bool,
+        max_velocity: &f64,
+    ) -> Result<(), &'static str> {
+        fs::write(path, self.to_gcode(feed_rates, max_velocity)).map_err(|e| {
+            error!("{}: {e}", path.display());
+            "Failed to write gcode file"
+        })
     }
 }
 
+/// mm to 3 places, without trailing zeros
+fn gcode_number(val: &f64) -> String {
+    let text = format!("{val:.3}");
+    let text = text.trim_end_matches('0').trim_end_matches('.');
+    match text {
+        "-0" => String::from("0"),
+        _ => String::from(text),
+    }
+}
+
+/// Text that can go in a gcode comment, which ends at the first closing parenthesis
+fn comment_text(text: &str) -> String {
+    text.replace(['(', ')'], "")
+}
+
 impl Display for PlotterProgram {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
@@ -629,3 +1538,82 @@ impl Display for PlotterProgram {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn g_words_are_exact_tenths() {
+        let mut code = GCode::new();
+        assert!(code.with_g(92.1).is_ok());
+        assert!(code.with_g(0.0).is_ok());
+        assert!(code.with_g(-1.0).is_err());
+        assert!(code.with_g(0.04).is_err());
+        assert!(code.with_g(1.5).is_err());
+        assert_eq!(
+            code.commands,
+            vec![GCommand::ClearOffset, GCommand::FastMove]
+        );
+    }
+
+    #[test]
+    fn problems_point_at_their_word() {
+        let (_, diagnostics) = parse_block(1, b"G1 X1 G-1 Y2");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].column, 7);
+
+        let line = b"G1 (z feed) X100 Y200 F-3";
+        let (code, _) = parse_block(1, line);
+        let Err((letter, _)) = ModalState::new().interpret(code, &0.1) else {
+            panic!("a negative feed rate was accepted");
+        };
+        assert_eq!(find_word(line, letter), 23);
+    }
+
+    #[test]
+    fn deleted_blocks_are_skipped() {
+        let (code, diagnostics) = parse_block(3, b"/G1 X10 Y10");
+        assert_eq!(code, GCode::default());
+        assert_eq!(diagnostics.len(), 1);
+        assert!(!diagnostics[0].is_error());
+    }
+
+    #[test]
+    fn rapids_are_timed_at_the_rapid_velocity() {
+        let mut physical = Physical::new(&crate::profile::MachineProfile::default());
+        let max_velocity = *physical.get_max_velocity();
+        physical.set_rapid_velocity(&(max_velocity / 4.0));
+        let move_to = |x, feed| PlotterInstruction::Move {
+            target: PositionMM::new([x, 0.0]),
+            feed,
+        };
+        // a second at a quarter of max velocity, then a second at half
+        let program = PlotterProgram::new(
+            vec![
+                move_to(0.0, Feed::Rapid),
+                move_to(max_velocity / 4.0, Feed::Rapid),
+                move_to(max_velocity * 3.0 / 4.0, Feed::Rate(max_velocity / 2.0)),
+            ],
+            &physical,
+        )
+        .unwrap();
+        assert!((program.time_remaining() - 2.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn stream_moves_from_where_the_pen_is() {
+        fn target(stream: &mut GCodeStream, line: &str) -> [f64; 2] {
+            let instructions = stream.block(line).unwrap();
+            let Some(PlotterInstruction::Move { target, .. }) = instructions.last() else {
+                panic!("{line} did not move");
+            };
+            [*target.x(), *target.y()]
+        }
+        let mut stream = GCodeStream::new(0.1);
+        stream.set_position(&PositionMM::new([100.0, 200.0]));
+        assert_eq!(target(&mut stream, "G0 X110"), [110.0, 200.0]);
+        stream.block("G91").unwrap();
+        assert_eq!(target(&mut stream, "G1 Y-5 F600"), [110.0, 195.0]);
+    }
+}
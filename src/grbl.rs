@@ -0,0 +1,459 @@
+use std::{
+    collections::VecDeque,
+    ffi::CStr,
+    fs::{File, OpenOptions},
+    io::{BufReader, Read, Write},
+    mem,
+    net::TcpListener,
+    os::{
+        fd::{AsRawFd, FromRawFd},
+        unix::fs::{symlink, OpenOptionsExt},
+    },
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use log::{error, info};
+
+use crate::{
+    controller::{ControlCommand, Controller, Status},
+    gcode::{upcoming_moves, GCodeStream, PlotterInstruction},
+    physical::Physical,
+};
+
+/// Greeting grbl prints after a reset, which senders wait for before streaming
+const BANNER: &str = "Grbl 1.1h ['$' for help]";
+
+/// How long to wait for a line before checking on the controller again
+const IDLE_WAIT: Duration = Duration::from_millis(250);
+
+/// Real time soft reset, ctrl-x
+const RESET: u8 = 0x18;
+
+/// `$` command that is not supported
+const ERROR_INVALID_STATEMENT: u8 = 3;
+/// `$` command sent while instructions are waiting to run
+const ERROR_NOT_IDLE: u8 = 8;
+/// Motion sent while in the alarm state
+const ERROR_ALARM_LOCK: u8 = 9;
+/// Block the gcode interpreter rejected
+const ERROR_UNSUPPORTED_COMMAND: u8 = 20;
+
+/// A move could not be made
+const ALARM_SOFT_LIMIT: u8 = 2;
+/// Reset while the pen was moving
+const ALARM_ABORT_CYCLE: u8 = 3;
+/// Homing did not find the home position
+const ALARM_HOMING_FAIL: u8 = 9;
+
+/// Where gcode senders connect
+pub enum Port {
+    /// Pseudo-terminal, with an optional symlink to its path
+    Pty(Option<PathBuf>),
+    /// "host:port" to listen on, one sender at a time
+    Tcp(String),
+}
+
+enum Input {
+    Line(String),
+    Reset,
+}
+
+/// What the reader threads need to answer real time commands
+struct Shared {
+    output: Mutex<Option<Box<dyn Write + Send>>>,
+    status: Arc<Mutex<Status>>,
+    /// Instructions are running or waiting to run
+    busy: AtomicBool,
+    /// Motion is locked out until `$H`, `$X` or a reset
+    alarm: AtomicBool,
+}
+
+impl Shared {
+    /// Write a line to the connected sender, if there is one
+    fn send(&self, line: &str) {
+        if let Some(output) = self.output.lock().unwrap().as_mut() {
+            if let Err(e) = write!(output, "{line}\r\n").and_then(|_| output.flush()) {
+                error!("{e}");
+            }
+        }
+    }
+    /// Send a real time command on to the controller, dropping it when there is nothing for it
+    /// to act on so it can not fire at the start of the next job
+    fn control(&self, control: &Sender<ControlCommand>, command: ControlCommand) {
+        let relevant = match command {
+            ControlCommand::Resume => self.status.lock().unwrap().is_paused(),
+            _ => self.busy.load(Ordering::SeqCst),
+        };
+        if relevant {
+            let _ = control.send(command);
+        }
+    }
+    /// Answer `?`
+    fn report(&self) {
+        let status = self.status.lock().unwrap().clone();
+        let state = if self.alarm.load(Ordering::SeqCst) {
+            "Alarm"
+        } else if status.is_paused() {
+            "Hold:0"
+        } else if self.busy.load(Ordering::SeqCst) {
+            "Run"
+        } else {
+            "Idle"
+        };
+        let [x, y] = status.get_position().unwrap_or([0.0; 2]);
+        self.send(&format!("<{state}|MPos:{x:.3},{y:.3},0.000>"));
+    }
+}
+
+/// Subset of the grbl serial protocol, so gcode senders can stream to the controller
+///
+/// Reader threads answer the real time commands `?`, `!`, `~` and ctrl-x straight away.
+/// Lines wait for `Grbl::pass` on the thread that owns the controller and are acknowledged
+/// once they are buffered, so the planner can look ahead at the moves a sender streams.
+pub struct Grbl {
+    input: Receiver<Input>,
+    shared: Arc<Shared>,
+    stream: GCodeStream,
+    arc_tolerance: f64,
+    /// Instructions acknowledged but not yet run
+    buffer: VecDeque<PlotterInstruction>,
+    /// Our end of the sender side of the pseudo-terminal, kept open so reads do not fail
+    /// while no sender has it open
+    _pty: Option<File>,
+}
+
+impl Grbl {
+    pub fn start(
+        port: Port,
+        arc_tolerance: f64,
+        controller: &Controller,
+    ) -> Result<Self, &'static str> {
+        let (input_sender, input) = mpsc::channel();
+        let control = controller.control();
+        let shared = Arc::new(Shared {
+            output: Mutex::new(None),
+            status: controller.shared_status(),
+            busy: AtomicBool::new(false),
+            alarm: AtomicBool::new(!controller.position_known()),
+        });
+        let mut pty = None;
+        match port {
+            Port::Pty(link) => {
+                let (master, slave, path) = open_pty()?;
+                info!("grbl on {}", path.display());
+                if let Some(link) = link {
+                    let _ = std::fs::remove_file(&link);
+                    symlink(&path, &link).map_err(|e| {
+                        error!("{}: {e}", link.display());
+                        "Failed to link pseudo-terminal"
+                    })?;
+                    info!("linked from {}", link.display());
+                }
+                let writer = master.try_clone().map_err(|e| {
+                    error!("{e}");
+                    "Failed to open pseudo-terminal"
+                })?;
+                *shared.output.lock().unwrap() = Some(Box::new(writer));
+                shared.send(BANNER);
+                let shared = shared.clone();
+                thread::spawn(move || read_input(master, &input_sender, &control, &shared));
+                pty = Some(slave);
+            }
+            Port::Tcp(address) => {
+                let listener = TcpListener::bind(&address).map_err(|e| {
+                    error!("{address}: {e}");
+                    "Failed to listen"
+                })?;
+                info!("grbl on tcp {address}");
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    for stream in listener.incoming() {
+                        let (stream, writer) = match stream.and_then(|s| Ok((s.try_clone()?, s))) {
+                            Ok(pair) => pair,
+                            Err(e) => {
+                                error!("{e}");
+                                continue;
+                            }
+                        };
+                        info!("sender connected from {:?}", stream.peer_addr());
+                        *shared.output.lock().unwrap() = Some(Box::new(writer));
+                        shared.send(BANNER);
+                        read_input(stream, &input_sender, &control, &shared);
+                        info!("sender disconnected");
+                        // drop whatever the sender left behind, like a reset
+                        *shared.output.lock().unwrap() = None;
+                        shared.control(&control, ControlCommand::Abort);
+                        let _ = input_sender.send(Input::Reset);
+                    }
+                });
+            }
+        }
+        let mut grbl = Grbl {
+            input,
+            shared,
+            stream: GCodeStream::new(arc_tolerance),
+            arc_tolerance,
+            buffer: VecDeque::new(),
+            _pty: pty,
+        };
+        grbl.follow_pen(controller);
+        Ok(grbl)
+    }
+
+    /// Have the gcode stream start from where the pen is, once that is known, so single axis
+    /// and relative moves are made from there rather than from the origin
+    fn follow_pen(&mut self, controller: &Controller) {
+        if let Some(mm) = controller.get_current_position() {
+            self.stream.set_position(&mm);
+        }
+    }
+
+    /// Take waiting lines into the buffer, then run the oldest buffered instruction
+    ///
+    /// Returns false once the sender side has stopped.
+    pub fn pass(&mut self, controller: &mut Controller) -> bool {
+        while self.buffer.len() <= *controller.look_ahead() {
+            let input = if self.buffer.is_empty() {
+                match controller.idle_while(|| self.input.recv_timeout(IDLE_WAIT)) {
+                    Ok(input) => input,
+                    Err(RecvTimeoutError::Timeout) => return true,
+                    Err(RecvTimeoutError::Disconnected) => return false,
+                }
+            } else {
+                match self.input.try_recv() {
+                    Ok(input) => input,
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return false,
+                }
+            };
+            self.accept(input, controller);
+        }
+        let Some(instruction) = self.buffer.pop_front() else {
+            return true;
+        };
+        let upcoming = upcoming_moves(self.buffer.make_contiguous(), controller.look_ahead());
+        if !self.shared.busy.swap(true, Ordering::SeqCst) {
+            // anything sent while idle raced the check in the reader
+            controller.drain_control();
+        }
+        if let Err(msg) = controller.run_instruction(&instruction, &upcoming) {
+            error!("{msg}");
+            self.buffer.clear();
+            let alarm = match msg {
+                "Program aborted" => ALARM_ABORT_CYCLE,
+                _ => ALARM_SOFT_LIMIT,
+            };
+            self.alarm(alarm);
+        }
+        self.shared
+            .busy
+            .store(!self.buffer.is_empty(), Ordering::SeqCst);
+        if self.buffer.is_empty() {
+            // the pen has stopped with nothing left to run
+            controller.save_state();
+        }
+        controller.publish_status();
+        true
+    }
+
+    fn alarm(&self, code: u8) {
+        self.shared.alarm.store(true, Ordering::SeqCst);
+        self.shared.send(&format!("ALARM:{code}"));
+    }
+
+    fn accept(&mut self, input: Input, controller: &mut Controller) {
+        match input {
+            Input::Reset => {
+                info!("reset");
+                self.buffer.clear();
+                self.stream = GCodeStream::new(self.arc_tolerance);
+                self.follow_pen(controller);
+                controller.drain_control();
+                let alarm = !controller.position_known();
+                self.shared.alarm.store(alarm, Ordering::SeqCst);
+                self.shared.send(BANNER);
+                if alarm {
+                    self.shared.send("[MSG:'$H'|'$X' to unlock]");
+                }
+            }
+            Input::Line(line) => match self.line(line.trim(), controller) {
+                Ok(()) => self.shared.send("ok"),
+                Err(code) => self.shared.send(&format!("error:{code}")),
+            },
+        }
+    }
+
+    fn line(&mut self, line: &str, controller: &mut Controller) -> Result<(), u8> {
+        if let Some(command) = line.strip_prefix('$') {
+            return self.system(command, controller);
+        }
+        if self.shared.alarm.load(Ordering::SeqCst) {
+            return Err(ERROR_ALARM_LOCK);
+        }
+        let instructions = self.stream.block(line).map_err(|reason| {
+            error!("{line}: {reason}");
+            ERROR_UNSUPPORTED_COMMAND
+        })?;
+        self.buffer.extend(instructions);
+        Ok(())
+    }
+
+    /// `$` commands
+    fn system(&mut self, command: &str, controller: &mut Controller) -> Result<(), u8> {
+        match command.to_ascii_uppercase().as_str() {
+            "" => self.shared.send("[HLP:$$ $H $X]"),
+            "$" => self.settings(controller.get_physical()),
+            "H" => {
+                if !self.buffer.is_empty() {
+                    return Err(ERROR_NOT_IDLE);
+                }
+                self.shared.busy.store(true, Ordering::SeqCst);
+                let homed = controller.home();
+                self.shared.busy.store(false, Ordering::SeqCst);
+                controller.save_state();
+                controller.publish_status();
+                match homed {
+                    Ok(()) => {
+                        self.shared.alarm.store(false, Ordering::SeqCst);
+                        self.follow_pen(controller);
+                    }
+                    Err(msg) => {
+                        error!("{msg}");
+                        self.alarm(ALARM_HOMING_FAIL);
+                    }
+                }
+            }
+            "X" => {
+                // unlocking only makes sense once we know where the pen is
+                if !controller.position_known() {
+                    return Err(ERROR_ALARM_LOCK);
+                }
+                self.shared.alarm.store(false, Ordering::SeqCst);
+                self.follow_pen(controller);
+                self.shared.send("[MSG:Caution: Unlocked]");
+            }
+            _ => return Err(ERROR_INVALID_STATEMENT),
+        }
+        Ok(())
+    }
+
+    /// `$$`, reporting the machine profile with grbl's setting numbers
+    fn settings(&self, physical: &Physical) {
+        let steps_per_mm = physical.mm_to_step(&1.0);
+        let rate = physical.get_max_velocity() * 60.0;
+        let acceleration = *physical.get_max_acceleration();
+        let [x0, x1] = physical.get_x_limits();
+        let [y0, y1] = physical.get_y_limits();
+        for (number, value) in [
+            (100, steps_per_mm),
+            (101, steps_per_mm),
+            (110, rate),
+            (111, rate),
+            (120, acceleration),
+            (121, acceleration),
+            (130, x1 - x0),
+            (131, y1 - y0),
+        ] {
+            self.shared.send(&format!("${number}={value:.3}"));
+        }
+    }
+}
+
+/// Pass lines from a sender on, answering real time commands straight away
+fn read_input(
+    reader: impl Read,
+    input: &Sender<Input>,
+    control: &Sender<ControlCommand>,
+    shared: &Shared,
+) {
+    let mut line = Vec::new();
+    for byte in BufReader::new(reader).bytes() {
+        let byte = match byte {
+            Ok(byte) => byte,
+            Err(e) => {
+                error!("{e}");
+                return;
+            }
+        };
+        match byte {
+            b'?' => shared.report(),
+            b'!' => shared.control(control, ControlCommand::Pause { lift_pen: false }),
+            b'~' => shared.control(control, ControlCommand::Resume),
+            RESET => {
+                shared.control(control, ControlCommand::Abort);
+                let _ = input.send(Input::Reset);
+            }
+            b'\n' | b'\r' => {
+                if !line.is_empty() {
+                    let text = String::from_utf8_lossy(&line).into_owned();
+                    line.clear();
+                    if input.send(Input::Line(text)).is_err() {
+                        return;
+                    }
+                }
+            }
+            // overrides and the other extended real time commands are not supported
+            0x80.. => {}
+            _ => line.push(byte),
+        }
+    }
+}
+
+/// Open a pseudo-terminal in raw mode
+///
+/// Returns our side, the sender side and the path senders open.
+fn open_pty() -> Result<(File, File, PathBuf), &'static str> {
+    let failed = |what: &str| {
+        error!("{what}: {}", std::io::Error::last_os_error());
+        "Failed to open pseudo-terminal"
+    };
+    // SAFETY: posix_openpt returns a new descriptor that the File takes ownership of
+    let master = unsafe {
+        let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+        if fd < 0 {
+            return Err(failed("posix_openpt"));
+        }
+        File::from_raw_fd(fd)
+    };
+    // SAFETY: calls on a descriptor we own, ptsname's result is copied before any other
+    // call could reuse its buffer
+    let path = unsafe {
+        if libc::grantpt(master.as_raw_fd()) != 0 || libc::unlockpt(master.as_raw_fd()) != 0 {
+            return Err(failed("unlockpt"));
+        }
+        let name = libc::ptsname(master.as_raw_fd());
+        if name.is_null() {
+            return Err(failed("ptsname"));
+        }
+        PathBuf::from(CStr::from_ptr(name).to_string_lossy().into_owned())
+    };
+    let slave = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY)
+        .open(&path)
+        .map_err(|e| {
+            error!("{}: {e}", path.display());
+            "Failed to open pseudo-terminal"
+        })?;
+    // no echo or line editing, senders see exactly what we write
+    // SAFETY: termios is plain data filled in by tcgetattr before it is used
+    unsafe {
+        let mut termios: libc::termios = mem::zeroed();
+        if libc::tcgetattr(slave.as_raw_fd(), &mut termios) != 0 {
+            return Err(failed("tcgetattr"));
+        }
+        libc::cfmakeraw(&mut termios);
+        if libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios) != 0 {
+            return Err(failed("tcsetattr"));
+        }
+    }
+    Ok((master, slave, path))
+}
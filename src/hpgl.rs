@@ -0,0 +1,169 @@
+use std::{collections::BTreeSet, fs, path::Path};
+
+use log::{error, warn};
+
+use crate::{
+    gcode::{Feed, PlotterInstruction},
+    position::PositionMM,
+};
+
+/// HP-GL plotter units per mm
+const UNITS_PER_MM: f64 = 40.0;
+
+/// Pen moves of an HP-GL file, in mm
+///
+/// IN, PU, PD, PA, PR and SP are followed; anything else is skipped with a warning. The first
+/// pen selected is taken to be the one fitted, and selecting a different pen after it pauses
+/// for the operator to change it.
+pub fn read_hpgl(path: &Path) -> Result<Vec<PlotterInstruction>, &'static str> {
+    let text = fs::read(path).map_err(|e| {
+        error!("{}: {e}", path.display());
+        "Failed to read hpgl file"
+    })?;
+    let mut plotter = Plotter::default();
+    let mut ignored = BTreeSet::new();
+    for (mnemonic, params) in commands(&String::from_utf8_lossy(&text)) {
+        let params: Vec<f64> = params
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|p| !p.is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .map_err(|_| {
+                error!("{mnemonic}{params}");
+                "Failed to parse hpgl parameters"
+            })?;
+        if !plotter.command(&mnemonic, &params)? {
+            ignored.insert(mnemonic);
+        }
+    }
+    if !ignored.is_empty() {
+        warn!("ignored unsupported hpgl: {ignored:?}");
+    }
+    if !plotter
+        .instructions
+        .iter()
+        .any(|instruction| matches!(instruction, PlotterInstruction::Move { .. }))
+    {
+        return Err("No moves in hpgl file");
+    }
+    plotter.pen(false);
+    Ok(plotter.instructions)
+}
+
+/// Split HP-GL into mnemonics and their parameter text
+fn commands(text: &str) -> Vec<(String, String)> {
+    let mut commands = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(first) = chars.next() {
+        // skip separators, terminators and device control escapes
+        if !first.is_ascii_alphabetic() {
+            continue;
+        }
+        let Some(second) = chars.next() else {
+            break;
+        };
+        let mnemonic = format!("{first}{second}").to_ascii_uppercase();
+        let mut params = String::new();
+        if mnemonic == "LB" {
+            // skip label text, which runs to ETX unless the terminator was changed with DT
+            for c in chars.by_ref() {
+                if c == '\x03' {
+                    break;
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| !c.is_ascii_alphabetic() && *c != ';') {
+                params.push(c);
+            }
+        }
+        commands.push((mnemonic, params));
+    }
+    commands
+}
+
+#[derive(Default)]
+struct Plotter {
+    relative: bool,
+    /// Pen state of the last instruction, if there has been one
+    pen_down: Option<bool>,
+    /// In plotter units
+    position: [f64; 2],
+    pen: Option<u32>,
+    instructions: Vec<PlotterInstruction>,
+}
+
+impl Plotter {
+    /// Returns false if the command is not supported
+    fn command(&mut self, mnemonic: &str, params: &[f64]) -> Result<bool, &'static str> {
+        match mnemonic {
+            "IN" => {
+                self.relative = false;
+                self.pen(false);
+            }
+            "PA" => {
+                self.relative = false;
+                self.moves(params)?;
+            }
+            "PR" => {
+                self.relative = true;
+                self.moves(params)?;
+            }
+            "PU" => {
+                self.pen(false);
+                self.moves(params)?;
+            }
+            "PD" => {
+                self.pen(true);
+                self.moves(params)?;
+            }
+            "SP" => self.select(params.first().map_or(0, |pen| *pen as u32)),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+    fn pen(&mut self, down: bool) {
+        if self.pen_down != Some(down) {
+            self.instructions.push(match down {
+                true => PlotterInstruction::PenDown,
+                false => PlotterInstruction::PenUp,
+            });
+            self.pen_down = Some(down);
+        }
+    }
+    /// SP, where pen 0 puts the pen away
+    fn select(&mut self, pen: u32) {
+        if pen == 0 {
+            self.pen(false);
+            return;
+        }
+        if self.pen.is_some_and(|fitted| fitted != pen) {
+            self.instructions
+                .push(PlotterInstruction::ChangePen(format!("pen {pen}")));
+            self.pen_down = Some(false);
+        }
+        self.pen = Some(pen);
+    }
+    /// Coordinate pairs of PA, PR, PU and PD
+    fn moves(&mut self, params: &[f64]) -> Result<(), &'static str> {
+        if !params.len().is_multiple_of(2) {
+            return Err("Odd number of hpgl coordinates");
+        }
+        for pair in params.chunks(2) {
+            if self.relative {
+                self.position[0] += pair[0];
+                self.position[1] += pair[1];
+            } else {
+                self.position = [pair[0], pair[1]];
+            }
+            let feed = match self.pen_down {
+                Some(true) => Feed::Max,
+                _ => Feed::Rapid,
+            };
+            self.instructions.push(PlotterInstruction::Move {
+                target: PositionMM::new(self.position.map(|units| units / UNITS_PER_MM)),
+                feed,
+            });
+        }
+        Ok(())
+    }
+}
@@ -1,26 +1,242 @@
+mod clock;
 mod controller;
 mod draw;
+mod dxf;
 mod gcode;
+mod grbl;
+mod hpgl;
 mod motor;
+mod pen;
 mod physical;
+mod planner;
 mod position;
 mod predictor;
+mod preview;
+mod profile;
+mod queue;
+mod render;
 mod scurve;
+mod server;
+mod simulate;
+mod state;
+mod svg;
 
-use crate::controller::Controller;
-use clap::Parser;
-use log::info;
+use crate::controller::{ControlCommand, Controller, Fit};
+use crate::gcode::{AxisLimit, PaperLimits, PlotterProgram, ReadOptions, DEFAULT_ARC_TOLERANCE};
+use crate::grbl::{Grbl, Port};
+use crate::motor::DriverKind;
+use crate::physical::Physical;
+use crate::position::PositionMM;
+use crate::preview::{preview, DEFAULT_PX_PER_MM};
+use crate::profile::MachineProfile;
+use crate::server::Server;
+use crate::simulate::simulate;
+use clap::{Parser, Subcommand};
+use log::{error, info};
 use simple_signal::{self, Signal};
 use std::error::Error;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
+/// What Ctrl-C asks of a running program when something can resume it
+const PAUSE: ControlCommand = ControlCommand::Pause { lift_pen: true };
+
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
+    /// gcode, svg, hpgl or dxf file to load
     #[arg(short, long)]
     gcode_path: Option<PathBuf>,
+    /// Machine profile toml, defaults to the built in machine
+    #[arg(long)]
+    profile: Option<PathBuf>,
+    /// Max distance in mm between a gcode arc or svg curve and the chords that replace it
+    #[arg(long, default_value_t = DEFAULT_ARC_TOLERANCE)]
+    arc_tolerance: f64,
+    /// dxf layers to draw, comma separated, defaults to every layer
+    #[arg(long, value_delimiter = ',')]
+    layers: Vec<String>,
+    /// Draw each dxf layer in turn, pausing for a pen change between them
+    #[arg(long)]
+    layer_passes: bool,
+    /// Stepper driver backend
+    #[arg(long, value_enum, default_value_t = DriverKind::Gpio)]
+    driver: DriverKind,
+    /// Speed in mm/s of G0 travel moves, overrides the machine profile
+    #[arg(long)]
+    rapid_velocity: Option<f64>,
+    /// File the position, paper limits and program are kept in across restarts
+    #[arg(long, default_value = "eveline_state.toml")]
+    state_path: PathBuf,
+    /// File the interactive job queue is kept in
+    #[arg(long, default_value = "eveline_queue.toml")]
+    queue_path: PathBuf,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run a gcode, svg, hpgl or dxf program on virtual hardware and render the stepped path to svg
+    Simulate {
+        gcode_path: PathBuf,
+        /// Starting pen position "x,y" in mm
+        #[arg(short, long)]
+        position: PositionMM,
+        /// Seconds of simulated time per pass of the motion loop
+        #[arg(long, default_value_t = 1e-4)]
+        tick: f64,
+        #[arg(short, long, default_value = "simulation.svg")]
+        output: PathBuf,
+    },
+    /// Report every error and warning in a gcode file
+    Check { gcode_path: PathBuf },
+    /// Write a gcode, svg, hpgl or dxf program out as gcode, fitted to the paper if asked
+    Export {
+        gcode_path: PathBuf,
+        #[arg(short, long)]
+        output: PathBuf,
+        /// Paper limits "x0,x1,y0,y1" in mm, to fit the program to
+        #[arg(long, required_if_eq_any = [("fit", "center"), ("fit", "scale")])]
+        paper: Option<PaperLimits>,
+        #[arg(long, value_enum, default_value_t = Fit::None)]
+        fit: Fit,
+        /// Give every feed move an F word, rather than leaving it to run as fast as it can
+        #[arg(long)]
+        feed_rates: bool,
+    },
+    /// Draw a program over the paper and the machine limits to an svg, or a png if the output
+    /// ends in .png, to check its scale and placement before plotting
+    Preview {
+        gcode_path: PathBuf,
+        #[arg(short, long, default_value = "preview.svg")]
+        output: PathBuf,
+        /// Paper limits "x0,x1,y0,y1" in mm, to draw and to fit the program to
+        #[arg(long, required_if_eq_any = [("fit", "center"), ("fit", "scale")])]
+        paper: Option<PaperLimits>,
+        #[arg(long, value_enum, default_value_t = Fit::None)]
+        fit: Fit,
+        /// Draw pen up travel too, faintly
+        #[arg(long)]
+        travel: bool,
+        /// Color strokes from blue to red in the order they are drawn
+        #[arg(long)]
+        gradient: bool,
+        /// Resolution of a png
+        #[arg(long, default_value_t = DEFAULT_PX_PER_MM)]
+        px_per_mm: f64,
+    },
+    /// Run a gcode, svg, hpgl or dxf program without asking anything, for scripts and services
+    Run {
+        gcode_path: PathBuf,
+        /// Current pen position "x,y" in mm
+        #[arg(short, long, required_unless_present = "home")]
+        position: Option<PositionMM>,
+        /// Find the position by homing against the endstops
+        #[arg(long, conflicts_with = "position")]
+        home: bool,
+        /// Paper limits "x0,x1,y0,y1" in mm
+        #[arg(long)]
+        paper: PaperLimits,
+        #[arg(long, value_enum, default_value_t = Fit::None)]
+        fit: Fit,
+        /// Instruction to start from
+        #[arg(long, default_value_t = 0)]
+        from: usize,
+        /// Start from the last pen lift at or before --from
+        #[arg(long)]
+        from_last_lift: bool,
+    },
+    /// Serve a control page and http api, with live status over a websocket
+    Serve {
+        /// Address to listen on, 0.0.0.0:8080 to reach it from the LAN
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        bind: String,
+        /// Directory uploaded gcode files are written to
+        #[arg(long, default_value = "uploads")]
+        upload_dir: PathBuf,
+    },
+    /// Speak a subset of the grbl serial protocol so gcode senders can stream to the plotter
+    Grbl {
+        /// Listen on "host:port" instead of a pseudo-terminal
+        #[arg(long)]
+        tcp: Option<String>,
+        /// Make a symlink to the pseudo-terminal, so senders can use a fixed path
+        #[arg(long, conflicts_with = "tcp")]
+        link: Option<PathBuf>,
+        /// Current pen position "x,y" in mm, otherwise the sender must home with $H
+        #[arg(short, long)]
+        position: Option<PositionMM>,
+    },
+}
+
+/// Turn signals into control commands and make passes over the controller until one
+/// returns false
+///
+/// interrupt: what SIGINT asks of a running program
+fn operate(
+    controller: &mut Controller,
+    interrupt: ControlCommand,
+    mut pass: impl FnMut(&mut Controller) -> bool,
+) {
+    let running = Arc::new(AtomicBool::new(true));
+
+    // SIGINT (Ctrl-C) usually pauses a running program and SIGQUIT (Ctrl-\) aborts it, keeping
+    // the process and its position. SIGTERM aborts and then stops the process. While the
+    // controller is idle, waiting on standard in or for a sender or request, any of them quits.
+    simple_signal::set_handler(&[Signal::Int, Signal::Quit, Signal::Term], {
+        let running = running.clone();
+        let control = controller.control();
+        let idle = controller.shared_idle();
+        move |signals| {
+            for signal in signals {
+                // holding the lock keeps the controller from starting a move
+                let waiting = idle.lock().unwrap();
+                if *waiting {
+                    // the session was saved once the motors last stopped, so there is nothing
+                    // left to write
+                    info!("Eveline done");
+                    std::process::exit(0);
+                }
+                drop(waiting);
+                let command = match signal {
+                    Signal::Term => {
+                        running.store(false, Ordering::SeqCst);
+                        ControlCommand::Abort
+                    }
+                    Signal::Int => interrupt,
+                    _ => ControlCommand::Abort,
+                };
+                let _ = control.send(command);
+            }
+        }
+    });
+
+    while running.load(Ordering::SeqCst) && pass(controller) {}
+    controller.save_state();
+}
+
+/// Paper limits in machine coordinates, moved by the y offset as the controller does
+fn machine_paper(paper: &PaperLimits, physical: &Physical) -> [AxisLimit; 2] {
+    let [x_limit, mut y_limit] = paper.axis_limits();
+    physical.adjust_paper_y_limit(&mut y_limit);
+    [x_limit, y_limit]
+}
+
+/// Read a program and fit it to the paper, if there is any
+fn read_fitted(
+    path: &Path,
+    physical: &Physical,
+    read_options: &ReadOptions,
+    paper: Option<&[AxisLimit; 2]>,
+    fit: &Fit,
+) -> Result<PlotterProgram, Box<dyn Error>> {
+    let mut program = PlotterProgram::read_file(path, physical, read_options)?;
+    if let Some([x_limit, y_limit]) = paper {
+        fit.apply(&mut program, x_limit, y_limit)?;
+    }
+    Ok(program)
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
@@ -28,23 +244,180 @@ fn main() -> Result<(), Box<dyn Error>> {
     info!("Eveline start");
 
     let args = Args::parse();
+    let profile = match &args.profile {
+        Some(path) => MachineProfile::load(path)?,
+        None => MachineProfile::default(),
+    };
+    let mut physical = Physical::new(&profile);
+    if let Some(velocity) = &args.rapid_velocity {
+        physical.set_rapid_velocity(velocity);
+    }
+    let read_options = ReadOptions::new(args.arc_tolerance, args.layers, args.layer_passes);
 
-    let mut controller = Controller::new(args.gcode_path);
-
-    let running = Arc::new(AtomicBool::new(true));
-
-    // When a SIGINT (Ctrl-C) or SIGTERM signal is caught, atomically set running to false.
-    simple_signal::set_handler(&[Signal::Int, Signal::Term], {
-        let running = running.clone();
-        move |_| {
-            running.store(false, Ordering::SeqCst);
+    match args.command {
+        Some(Command::Simulate {
+            gcode_path,
+            position,
+            tick,
+            output,
+        }) => {
+            let program = PlotterProgram::read_file(&gcode_path, &physical, &read_options)?;
+            simulate(physical, program, position, tick, &output)?;
+            return Ok(());
         }
-    });
+        Some(Command::Check { gcode_path }) => {
+            let diagnostics =
+                PlotterProgram::check_gcode_file(&gcode_path, &physical, &args.arc_tolerance);
+            for diagnostic in &diagnostics {
+                println!("{diagnostic}");
+            }
+            let errors = diagnostics.iter().filter(|d| d.is_error()).count();
+            println!(
+                "{errors} error(s), {} warning(s)",
+                diagnostics.len() - errors
+            );
+            if errors > 0 {
+                return Err("gcode check failed".into());
+            }
+            return Ok(());
+        }
+        Some(Command::Export {
+            gcode_path,
+            output,
+            paper,
+            fit,
+            feed_rates,
+        }) => {
+            let paper = paper.map(|paper| machine_paper(&paper, &physical));
+            let program = read_fitted(&gcode_path, &physical, &read_options, paper.as_ref(), &fit)?;
+            program.write_gcode_file(&output, &feed_rates, physical.get_max_velocity())?;
+            info!("wrote {}", output.display());
+            return Ok(());
+        }
+        Some(Command::Preview {
+            gcode_path,
+            output,
+            paper,
+            fit,
+            travel,
+            gradient,
+            px_per_mm,
+        }) => {
+            let paper = paper.map(|paper| machine_paper(&paper, &physical));
+            let program = read_fitted(&gcode_path, &physical, &read_options, paper.as_ref(), &fit)?;
+            preview(
+                &program,
+                &physical,
+                paper.as_ref(),
+                &travel,
+                &gradient,
+                &px_per_mm,
+                &output,
+            )?;
+            return Ok(());
+        }
+        Some(Command::Run {
+            gcode_path,
+            position,
+            home: _,
+            paper,
+            fit,
+            from,
+            from_last_lift,
+        }) => {
+            // without a servo the pen would be lifted by asking on standard in
+            if physical.get_pen_lift().is_none() && matches!(args.driver, DriverKind::Gpio) {
+                error!("run needs a [pen_lift] servo in the profile");
+                return Err("no pen lift servo".into());
+            }
+            let mut controller = Controller::new(
+                physical,
+                Some(gcode_path),
+                read_options,
+                args.driver,
+                args.state_path,
+            );
+            controller.disable_prompts();
+            // clap asks for --home when there is no position
+            match position {
+                Some(mm) => controller.set_current_position(mm)?,
+                None => controller.home()?,
+            }
+            let [x_limit, y_limit] = paper.axis_limits();
+            controller.set_paper_limits(x_limit, y_limit);
+            controller.fit_program(&fit)?;
+            controller.start_program(&from, &from_last_lift)?;
+            // nothing could resume a pause, so Ctrl-C gives up on the program
+            operate(&mut controller, ControlCommand::Abort, |controller| {
+                controller.update();
+                controller.is_running_program()
+            });
+            if !controller.program_finished() {
+                controller.lift_pen();
+                return Err("program did not finish".into());
+            }
+            info!("Eveline done");
+            return Ok(());
+        }
+        Some(Command::Serve { bind, upload_dir }) => {
+            let mut controller = Controller::new(
+                physical,
+                args.gcode_path,
+                read_options,
+                args.driver,
+                args.state_path,
+            );
+            controller.disable_prompts();
+            controller.publish_status();
+            let server = Server::start(&bind, upload_dir, &controller)?;
+            operate(&mut controller, PAUSE, |controller| server.pass(controller));
+            info!("Eveline done");
+            return Ok(());
+        }
+        Some(Command::Grbl {
+            tcp,
+            link,
+            position,
+        }) => {
+            let mut controller = Controller::new(
+                physical,
+                args.gcode_path,
+                read_options,
+                args.driver,
+                args.state_path,
+            );
+            controller.disable_prompts();
+            if let Some(mm) = position {
+                controller.set_current_position(mm)?;
+            }
+            controller.publish_status();
+            let port = match tcp {
+                Some(address) => Port::Tcp(address),
+                None => Port::Pty(link),
+            };
+            let mut grbl = Grbl::start(port, args.arc_tolerance, &controller)?;
+            operate(&mut controller, PAUSE, |controller| grbl.pass(controller));
+            info!("Eveline done");
+            return Ok(());
+        }
+        None => {}
+    }
+
+    let mut controller = Controller::new(
+        physical,
+        args.gcode_path,
+        read_options,
+        args.driver,
+        args.state_path,
+    );
+    controller.offer_restore();
+    controller.load_queue(args.queue_path)?;
 
-    // Operate until running is set to false.
-    while running.load(Ordering::SeqCst) {
+    // Operate until the operator quits or the process is told to stop.
+    operate(&mut controller, PAUSE, |controller| {
         controller.update();
-    }
+        !controller.quit_requested()
+    });
     info!("Eveline done");
     Ok(())
 }
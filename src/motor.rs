@@ -1,10 +1,26 @@
-use std::{fmt::Display, time::Instant};
+use std::{
+    fmt::Display,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc, Mutex,
+    },
+};
 
-use rppal::gpio::{Gpio, OutputPin};
+use clap::ValueEnum;
+use rppal::gpio::{Gpio, InputPin, OutputPin};
+
+use crate::{
+    clock::Clock,
+    pen::{ManualPenLift, PenLift, ServoPenLift, SimulatedPenLift},
+    physical::Physical,
+    position::PositionMM,
+};
 
 // Gpio uses BCM pin numbering. BCM GPIO 23 is tied to physical pin 16.
 const RIGHT_PINS: [u8; 4] = [4, 22, 17, 27];
 const LEFT_PINS: [u8; 4] = [12, 21, 16, 20];
+const RIGHT_ENDSTOP_PIN: u8 = 6;
+const LEFT_ENDSTOP_PIN: u8 = 5;
 const PWM_FREQ: f64 = 200.0;
 pub const STEP_DIVISION: usize = 1;
 
@@ -15,31 +31,168 @@ pub enum StepInstruction {
     Hold,
 }
 
-pub struct Motor {
-    pins: [OutputPin; 4],
-    current: usize,
-    position: i32,
-    side: Side,
-    current_pwm: usize,
-    current_on: usize,
-    min_seconds_per_step: f64,
-    time_last_step: Instant,
+/// Direction a driver turns the motor shaft for a single step
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rotation {
+    ClockWise,
+    CounterClockWise,
 }
 
-pub enum Side {
-    Left,
-    Right,
+/// Hardware backend that turns a stepper motor one step at a time
+pub trait StepperDriver: Send {
+    fn step(&mut self, rotation: Rotation);
 }
-impl Display for Side {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+
+/// Switch that closes when a cord is reeled in to its home length
+pub trait Endstop: Send {
+    fn triggered(&mut self) -> bool;
+}
+
+/// Which `StepperDriver` and `Endstop` backend to build motors with
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum DriverKind {
+    /// 4-pin unipolar driver on the Raspberry Pi GPIO header
+    Gpio,
+    /// In-memory driver that only records the steps taken, with simulated endstops
+    Recording,
+}
+
+/// Steps taken by recording drivers, in the order they were taken
+pub type StepJournal = Arc<Mutex<Vec<(Side, Rotation)>>>;
+
+/// Stand in for the cords and endstops of a machine built with recording drivers
+#[derive(Clone, Default)]
+pub struct SimulatedHardware {
+    journal: StepJournal,
+    /// Length in steps of each cord
+    cords: [Arc<AtomicI64>; 2],
+    /// Length in steps at which each cord's endstop closes
+    triggers: [i64; 2],
+}
+
+impl SimulatedHardware {
+    /// start: where the pen really is
+    pub fn new(start: &PositionMM, physical: &Physical) -> Self {
+        let cords = physical.get_motor_dist(start);
+        let triggers = physical.get_motor_dist(physical.get_home());
+        SimulatedHardware {
+            journal: StepJournal::default(),
+            cords: [0, 1].map(|i| Arc::new(AtomicI64::new(cords[i]))),
+            triggers: [triggers[0], triggers[1]],
+        }
+    }
+    pub fn journal(&self) -> &StepJournal {
+        &self.journal
+    }
+}
+
+impl DriverKind {
+    pub fn build(&self, side: Side, hardware: &SimulatedHardware) -> Box<dyn StepperDriver> {
         match self {
-            Side::Left => write!(f, "L"),
-            Side::Right => write!(f, "R"),
+            DriverKind::Gpio => Box::new(UnipolarDriver::new(side)),
+            DriverKind::Recording => Box::new(RecordingDriver::new(
+                side,
+                hardware.journal.clone(),
+                hardware.cords[side.index()].clone(),
+            )),
+        }
+    }
+    /// Servo pen lift if the machine has one, otherwise the operator is asked
+    pub fn build_pen_lift(&self, physical: &Physical) -> Box<dyn PenLift> {
+        match (self, physical.get_pen_lift()) {
+            (DriverKind::Gpio, Some(servo)) => Box::new(ServoPenLift::new(servo)),
+            (DriverKind::Gpio, None) => Box::new(ManualPenLift),
+            (DriverKind::Recording, servo) => Box::new(SimulatedPenLift::new(servo)),
+        }
+    }
+    pub fn build_endstop(&self, side: Side, hardware: &SimulatedHardware) -> Box<dyn Endstop> {
+        match self {
+            DriverKind::Gpio => Box::new(GpioEndstop::new(side)),
+            DriverKind::Recording => Box::new(SimulatedEndstop {
+                cord: hardware.cords[side.index()].clone(),
+                trigger: hardware.triggers[side.index()],
+            }),
         }
     }
 }
-impl Motor {
-    pub fn new(side: Side, min_seconds_per_step: f64) -> Motor {
+
+/// Records steps to a shared journal instead of driving pins
+pub struct RecordingDriver {
+    side: Side,
+    journal: StepJournal,
+    cord: Arc<AtomicI64>,
+}
+
+impl RecordingDriver {
+    pub fn new(side: Side, journal: StepJournal, cord: Arc<AtomicI64>) -> Self {
+        RecordingDriver {
+            side,
+            journal,
+            cord,
+        }
+    }
+}
+
+impl StepperDriver for RecordingDriver {
+    fn step(&mut self, rotation: Rotation) {
+        self.journal.lock().unwrap().push((self.side, rotation));
+        let change = match self.side.step_instruction(&rotation) {
+            StepInstruction::StepLonger => 1,
+            StepInstruction::StepShorter => -1,
+            StepInstruction::Hold => 0,
+        };
+        self.cord.fetch_add(change, Ordering::SeqCst);
+    }
+}
+
+/// Closes once the simulated cord is reeled in to its trigger length
+pub struct SimulatedEndstop {
+    cord: Arc<AtomicI64>,
+    trigger: i64,
+}
+
+impl Endstop for SimulatedEndstop {
+    fn triggered(&mut self) -> bool {
+        self.cord.load(Ordering::SeqCst) <= self.trigger
+    }
+}
+
+/// Normally open switch between a GPIO pin and ground
+pub struct GpioEndstop {
+    pin: InputPin,
+}
+
+impl GpioEndstop {
+    pub fn new(side: Side) -> GpioEndstop {
+        let pin_num = match side {
+            Side::Left => LEFT_ENDSTOP_PIN,
+            Side::Right => RIGHT_ENDSTOP_PIN,
+        };
+        let pin = Gpio::new()
+            .unwrap()
+            .get(pin_num)
+            .unwrap()
+            .into_input_pullup();
+        GpioEndstop { pin }
+    }
+}
+
+impl Endstop for GpioEndstop {
+    fn triggered(&mut self) -> bool {
+        self.pin.is_low()
+    }
+}
+
+/// Drives the 28BYJ-48 style board over 4 GPIO pins
+pub struct UnipolarDriver {
+    pins: [OutputPin; 4],
+    current: usize,
+    current_pwm: usize,
+    current_on: usize,
+}
+
+impl UnipolarDriver {
+    pub fn new(side: Side) -> UnipolarDriver {
         // init output pins
         let pin_nums = match side {
             Side::Left => LEFT_PINS,
@@ -70,52 +223,13 @@ impl Motor {
                 pins[current_pwm].set_pwm_frequency(PWM_FREQ, 0.0).unwrap();
             }
         }
-        Motor {
+        UnipolarDriver {
             pins,
             current,
-            position: 0,
-            side,
             current_pwm,
             current_on,
-            min_seconds_per_step,
-            time_last_step: Instant::now(),
         }
     }
-    fn step_shorter(&mut self) {
-        match self.side {
-            Side::Left => self.step_counter_clock_wise(),
-            Side::Right => self.step_clock_wise(),
-        };
-        self.position -= 1;
-    }
-    fn step_longer(&mut self) {
-        match self.side {
-            Side::Left => self.step_clock_wise(),
-            Side::Right => self.step_counter_clock_wise(),
-        };
-        self.position += 1;
-    }
-    pub fn step(&mut self, instruction: &StepInstruction) -> Result<(), ()> {
-        match instruction {
-            StepInstruction::StepLonger | StepInstruction::StepShorter => {
-                if self.time_last_step.elapsed().as_secs_f64() < self.min_seconds_per_step {
-                    return Err(());
-                }
-                self.time_last_step = Instant::now();
-            }
-            StepInstruction::Hold => return Ok(()),
-        }
-        match instruction {
-            StepInstruction::StepLonger => {
-                self.step_longer();
-            }
-            StepInstruction::StepShorter => {
-                self.step_shorter();
-            }
-            StepInstruction::Hold => {}
-        }
-        Ok(())
-    }
     /// Update pins for whole step mode
     fn update_pins_whole_step(&mut self) {
         let main_pin = self.current;
@@ -137,6 +251,8 @@ impl Motor {
         self.current_on = main_pin;
         self.current_pwm = secondary_pin;
     }
+    // STEP_DIVISION is a tuning constant, so some of these branches are dead at any one setting
+    #[allow(clippy::modulo_one, clippy::absurd_extreme_comparisons)]
     fn update_pins(&mut self) {
         if STEP_DIVISION == 1 {
             return self.update_pins_whole_step();
@@ -209,3 +325,147 @@ impl Motor {
         self.update_pins();
     }
 }
+
+impl StepperDriver for UnipolarDriver {
+    fn step(&mut self, rotation: Rotation) {
+        match rotation {
+            Rotation::ClockWise => self.step_clock_wise(),
+            Rotation::CounterClockWise => self.step_counter_clock_wise(),
+        }
+    }
+}
+
+pub struct Motor {
+    driver: Box<dyn StepperDriver>,
+    endstop: Box<dyn Endstop>,
+    position: i32,
+    side: Side,
+    min_seconds_per_step: f64,
+    time_last_step: f64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Side {
+    Left,
+    Right,
+}
+impl Display for Side {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Side::Left => write!(f, "L"),
+            Side::Right => write!(f, "R"),
+        }
+    }
+}
+impl Side {
+    /// Index of this side's motor in `Physical` and `PositionStep`
+    pub fn index(&self) -> usize {
+        match self {
+            Side::Left => 0,
+            Side::Right => 1,
+        }
+    }
+    /// Which way the cord moved when this side's motor turned
+    pub fn step_instruction(&self, rotation: &Rotation) -> StepInstruction {
+        match (self, rotation) {
+            (Side::Left, Rotation::ClockWise) | (Side::Right, Rotation::CounterClockWise) => {
+                StepInstruction::StepLonger
+            }
+            (Side::Left, Rotation::CounterClockWise) | (Side::Right, Rotation::ClockWise) => {
+                StepInstruction::StepShorter
+            }
+        }
+    }
+}
+impl Motor {
+    pub fn new(
+        side: Side,
+        min_seconds_per_step: f64,
+        driver: Box<dyn StepperDriver>,
+        endstop: Box<dyn Endstop>,
+    ) -> Motor {
+        Motor {
+            driver,
+            endstop,
+            position: 0,
+            side,
+            min_seconds_per_step,
+            time_last_step: f64::NEG_INFINITY,
+        }
+    }
+    fn step_shorter(&mut self) {
+        match self.side {
+            Side::Left => self.driver.step(Rotation::CounterClockWise),
+            Side::Right => self.driver.step(Rotation::ClockWise),
+        };
+        self.position -= 1;
+    }
+    fn step_longer(&mut self) {
+        match self.side {
+            Side::Left => self.driver.step(Rotation::ClockWise),
+            Side::Right => self.driver.step(Rotation::CounterClockWise),
+        };
+        self.position += 1;
+    }
+    pub fn endstop_triggered(&mut self) -> bool {
+        self.endstop.triggered()
+    }
+    pub fn step(&mut self, instruction: &StepInstruction, clock: &dyn Clock) -> Result<(), ()> {
+        match instruction {
+            StepInstruction::StepLonger | StepInstruction::StepShorter => {
+                let now = clock.now();
+                if now - self.time_last_step < self.min_seconds_per_step {
+                    return Err(());
+                }
+                self.time_last_step = now;
+            }
+            StepInstruction::Hold => return Ok(()),
+        }
+        match instruction {
+            StepInstruction::StepLonger => {
+                self.step_longer();
+            }
+            StepInstruction::StepShorter => {
+                self.step_shorter();
+            }
+            StepInstruction::Hold => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::VirtualClock;
+
+    fn recording_motor(min_seconds_per_step: f64) -> (Motor, StepJournal) {
+        let hardware = SimulatedHardware::default();
+        let driver = DriverKind::Recording.build(Side::Left, &hardware);
+        let endstop = DriverKind::Recording.build_endstop(Side::Left, &hardware);
+        let motor = Motor::new(Side::Left, min_seconds_per_step, driver, endstop);
+        (motor, hardware.journal().clone())
+    }
+
+    #[test]
+    fn steps_closer_than_the_min_interval_are_refused() {
+        let clock = VirtualClock::new(0.004);
+        let (mut motor, journal) = recording_motor(0.01);
+        assert!(motor.step(&StepInstruction::StepLonger, &clock).is_ok());
+        for _ in 0..2 {
+            clock.tick();
+            assert!(motor.step(&StepInstruction::StepLonger, &clock).is_err());
+            // holding is never too fast
+            assert!(motor.step(&StepInstruction::Hold, &clock).is_ok());
+        }
+        clock.tick();
+        assert!(motor.step(&StepInstruction::StepShorter, &clock).is_ok());
+        assert_eq!(
+            *journal.lock().unwrap(),
+            vec![
+                (Side::Left, Rotation::ClockWise),
+                (Side::Left, Rotation::CounterClockWise)
+            ]
+        );
+    }
+}
@@ -0,0 +1,111 @@
+use std::io;
+
+use log::info;
+use rppal::gpio::{Gpio, OutputPin};
+
+use crate::{clock::Clock, profile::ServoProfile};
+
+/// Raises and lowers the pen
+pub trait PenLift: Send {
+    fn up(&mut self, clock: &dyn Clock);
+    fn down(&mut self, clock: &dyn Clock);
+}
+
+/// Asks the operator to move the pen by hand
+pub struct ManualPenLift;
+
+impl ManualPenLift {
+    fn wait_for_enter() {
+        if let Err(error) = io::stdin().read_line(&mut String::new()) {
+            log::error!("error: {error}");
+        }
+    }
+}
+
+impl PenLift for ManualPenLift {
+    fn up(&mut self, _clock: &dyn Clock) {
+        println!("Remove pen and hit enter");
+        ManualPenLift::wait_for_enter();
+    }
+    fn down(&mut self, _clock: &dyn Clock) {
+        println!("Insert pen and hit enter");
+        ManualPenLift::wait_for_enter();
+    }
+}
+
+/// Hobby servo driven with software PWM on a GPIO pin
+pub struct ServoPenLift {
+    pin: OutputPin,
+    servo: ServoProfile,
+}
+
+impl ServoPenLift {
+    pub fn new(servo: &ServoProfile) -> Self {
+        let pin = Gpio::new()
+            .unwrap()
+            .get(*servo.get_pin())
+            .unwrap()
+            .into_output();
+        ServoPenLift {
+            pin,
+            servo: servo.clone(),
+        }
+    }
+    fn move_to(&mut self, angle: f64, settle_seconds: f64, clock: &dyn Clock) {
+        self.pin
+            .set_pwm(ServoProfile::PERIOD, self.servo.pulse_width(&angle))
+            .unwrap();
+        clock.sleep(&settle_seconds);
+    }
+}
+
+impl PenLift for ServoPenLift {
+    fn up(&mut self, clock: &dyn Clock) {
+        self.move_to(
+            *self.servo.get_up_angle(),
+            *self.servo.get_up_settle_seconds(),
+            clock,
+        );
+    }
+    fn down(&mut self, clock: &dyn Clock) {
+        self.move_to(
+            *self.servo.get_down_angle(),
+            *self.servo.get_down_settle_seconds(),
+            clock,
+        );
+    }
+}
+
+/// Pen lift for recording drivers, which only takes up time
+pub struct SimulatedPenLift {
+    up_settle_seconds: f64,
+    down_settle_seconds: f64,
+}
+
+impl SimulatedPenLift {
+    /// servo: settle delays to simulate, if a servo is configured
+    pub fn new(servo: &Option<ServoProfile>) -> Self {
+        let (up_settle_seconds, down_settle_seconds) = match servo {
+            Some(servo) => (
+                *servo.get_up_settle_seconds(),
+                *servo.get_down_settle_seconds(),
+            ),
+            None => (0.0, 0.0),
+        };
+        SimulatedPenLift {
+            up_settle_seconds,
+            down_settle_seconds,
+        }
+    }
+}
+
+impl PenLift for SimulatedPenLift {
+    fn up(&mut self, clock: &dyn Clock) {
+        info!("pen up");
+        clock.sleep(&self.up_settle_seconds);
+    }
+    fn down(&mut self, clock: &dyn Clock) {
+        info!("pen down");
+        clock.sleep(&self.down_settle_seconds);
+    }
+}
@@ -1,11 +1,13 @@
 use std::fmt::Display;
 
 use crate::{
-    gcode::AxisLimit,
+    gcode::{AxisLimit, Feed},
     motor::STEP_DIVISION,
     position::{PositionMM, PositionStep, PositionStepFloat},
+    profile::{MachineProfile, ServoProfile},
 };
 
+#[derive(Clone)]
 pub struct Physical {
     motor_pos: [PositionMM; 2],
     x_limits: [f64; 2],
@@ -14,35 +16,48 @@ pub struct Physical {
     steps_per_mm: f64,
     mm_per_step: f64,
     max_velocity: f64,
+    /// Velocity of G0 travel moves
+    rapid_velocity: f64,
     min_seconds_per_step: f64,
+    max_acceleration: f64,
+    max_jerk: f64,
+    look_ahead: usize,
+    junction_deviation: f64,
+    /// Longest cord in steps, to the far bottom corner of the frame
+    max_cord_steps: f64,
+    /// Pen position when both endstops have just closed
+    home: PositionMM,
+    homing_velocity: f64,
+    pen_lift: Option<ServoProfile>,
 }
 
 impl Display for Physical {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "motor_pos: [{}, {}], steps_per_mm: {}, mm_per_step: {}, max_velocity: {}",
+            "motor_pos: [{}, {}], steps_per_mm: {}, mm_per_step: {}, max_velocity: {}, rapid_velocity: {}",
             self.motor_pos[0],
             self.motor_pos[1],
             self.steps_per_mm,
             self.mm_per_step,
-            self.max_velocity
+            self.max_velocity,
+            self.rapid_velocity
         )
     }
 }
 
 impl Physical {
-    pub fn new() -> Physical {
-        let mm = PositionMM::new;
-        let motor_pos = [mm([0.0, 368.8]), mm([297.0, 368.8])];
-        let spool_radius: f64 = 5.75;
-        let gear_ratio: f64 = (59.0_f64 / 17.0_f64).powi(2);
-        let motor_steps_per_revolution = 100.0 * STEP_DIVISION as f64;
+    pub fn new(profile: &MachineProfile) -> Physical {
+        let motor_pos = profile.get_motor_positions().map(PositionMM::new);
+        let spool_radius = profile.get_spool_radius();
+        let gear_ratio = profile.get_gear_ratio();
+        let motor_steps_per_revolution =
+            profile.get_motor_steps_per_revolution() * STEP_DIVISION as f64;
         // left, right
         let spool_circumfrence = spool_radius * 2.0 * std::f64::consts::PI;
         // steps_per_mm is aprox 33.2
         let steps_per_mm = motor_steps_per_revolution * gear_ratio / spool_circumfrence;
-        let max_rpm = 100.0_f64;
+        let max_rpm = profile.get_max_rpm();
         // max_revs_per_second is about 1.7
         let max_revs_per_second = max_rpm / 60.0;
         // max_steps_per_second is about 170
@@ -50,26 +65,43 @@ impl Physical {
         let min_seconds_per_step = max_steps_per_second.recip();
         // max velocity is about 5 mm/s
         let max_velocity = max_steps_per_second / steps_per_mm;
-        let x_limits = [45.0, 250.0];
-        let y_limits = [70.0, 328.0];
-        let y_offset = 10.0;
+        // the frame spans between the motors and down to y = 0
+        let frame_corners = [
+            PositionMM::new([motor_pos[0][0], 0.0]),
+            PositionMM::new([motor_pos[1][0], 0.0]),
+        ];
+        let max_cord = motor_pos
+            .iter()
+            .flat_map(|m| frame_corners.iter().map(|c| m.dist(c)))
+            .fold(0.0, f64::max);
+        let rapid_velocity = profile
+            .get_rapid_velocity()
+            .map_or(max_velocity, |v| v.min(max_velocity));
         Physical {
             motor_pos,
             steps_per_mm,
             mm_per_step: 1.0 / steps_per_mm,
             max_velocity,
-            x_limits,
-            y_limits,
-            y_offset,
+            rapid_velocity,
+            x_limits: *profile.get_x_limits(),
+            y_limits: *profile.get_y_limits(),
+            y_offset: *profile.get_y_offset(),
             min_seconds_per_step,
+            max_acceleration: *profile.get_max_acceleration(),
+            max_jerk: *profile.get_max_jerk(),
+            look_ahead: *profile.get_look_ahead(),
+            junction_deviation: *profile.get_junction_deviation(),
+            max_cord_steps: max_cord * steps_per_mm,
+            home: PositionMM::new(*profile.get_home()),
+            homing_velocity: *profile.get_homing_velocity(),
+            pen_lift: profile.get_pen_lift().clone(),
         }
     }
     pub fn adjust_paper_y_limit(&self, y_limit: &mut AxisLimit) {
         y_limit.offset(&self.y_offset);
     }
     pub fn in_bounds(&self, position: &PositionMM) -> bool {
-        let y_limits = self.y_limits.map(|x| x + self.y_offset);
-        position.in_bounds(&self.x_limits, &y_limits)
+        position.in_bounds(&self.x_limits, &self.get_y_limits())
     }
     pub fn mm_to_step(&self, dist: &f64) -> f64 {
         dist * self.steps_per_mm
@@ -88,19 +120,94 @@ impl Physical {
     pub fn get_motor_position(&self, index: usize) -> &PositionMM {
         &self.motor_pos[index]
     }
+    pub fn get_x_limits(&self) -> &[f64; 2] {
+        &self.x_limits
+    }
+    /// Y limits of the drawable area, including the y offset
+    pub fn get_y_limits(&self) -> [f64; 2] {
+        self.y_limits.map(|y| y + self.y_offset)
+    }
     pub fn get_max_velocity(&self) -> &f64 {
         &self.max_velocity
     }
+    pub fn get_rapid_velocity(&self) -> &f64 {
+        &self.rapid_velocity
+    }
+    /// Set the G0 travel velocity in mm/s, capped at max velocity
+    pub fn set_rapid_velocity(&mut self, velocity: &f64) {
+        self.rapid_velocity = velocity.min(self.max_velocity);
+    }
+    /// Velocity in mm/s a move with the given feed should coast at
+    pub fn feed_velocity(&self, feed: &Feed) -> f64 {
+        match feed {
+            Feed::Rapid => self.rapid_velocity,
+            Feed::Rate(rate) => rate.min(self.max_velocity),
+            Feed::Max => self.max_velocity,
+        }
+    }
+    /// Servo that lifts the pen, if there is one
+    pub fn get_pen_lift(&self) -> &Option<ServoProfile> {
+        &self.pen_lift
+    }
+    pub fn get_home(&self) -> &PositionMM {
+        &self.home
+    }
+    /// Seconds between steps while homing
+    pub fn get_homing_seconds_per_step(&self) -> f64 {
+        (self.homing_velocity * self.steps_per_mm)
+            .recip()
+            .max(self.min_seconds_per_step)
+    }
+    /// Most steps a cord can be reeled in by
+    pub fn get_max_cord_steps(&self) -> &f64 {
+        &self.max_cord_steps
+    }
+    pub fn get_max_acceleration(&self) -> &f64 {
+        &self.max_acceleration
+    }
+    pub fn get_max_jerk(&self) -> &f64 {
+        &self.max_jerk
+    }
+    pub fn get_look_ahead(&self) -> &usize {
+        &self.look_ahead
+    }
+    pub fn get_junction_deviation(&self) -> &f64 {
+        &self.junction_deviation
+    }
     pub fn get_min_seconds_per_step(&self) -> &f64 {
         &self.min_seconds_per_step
     }
-    pub fn step_to_mm(&self, step: &usize) -> f64 {
+    /// Check cord lengths in steps are ones the cords can take
+    pub fn check_step(&self, step: &PositionStep) -> Result<(), &'static str> {
+        let d = self.mm_to_step(&self.motor_pos[0].dist(&self.motor_pos[1]));
+        let (r0, r1) = (step[0] as f64, step[1] as f64);
+        if r0 <= 0.0 || r1 <= 0.0 {
+            Err("Cord length must be positive")
+        } else if r0.max(r1) > self.max_cord_steps {
+            Err("Cord is longer than the frame")
+        } else if r0 + r1 < d || (r0 - r1).abs() > d {
+            Err("Cord lengths do not meet")
+        } else {
+            Ok(())
+        }
+    }
+    /// Check the pen could be at a position, hanging below the motors
+    pub fn check_position(&self, mm: &PositionMM) -> Result<(), &'static str> {
+        let along = self.motor_pos[0].get_direction(&self.motor_pos[1]);
+        let above =
+            along[0] * (mm[1] - self.motor_pos[0][1]) - along[1] * (mm[0] - self.motor_pos[0][0]);
+        if above >= 0.0 {
+            return Err("Position must be below the motors");
+        }
+        self.check_step(&self.get_motor_dist(mm))
+    }
+    pub fn step_to_mm(&self, step: &i64) -> f64 {
         *step as f64 * self.mm_per_step
     }
 }
 
 impl Default for Physical {
     fn default() -> Self {
-        Self::new()
+        Self::new(&MachineProfile::default())
     }
 }
@@ -0,0 +1,141 @@
+use crate::{position::PositionMM, scurve::SCurveSolver};
+
+/// Picks the velocity to carry through the junction at the end of each move
+///
+/// The backward pass runs over a window of upcoming moves and assumes the machine stops at
+/// the end of it, so every junction can still be slowed for. The forward pass is the move
+/// itself, starting at the velocity the last move actually finished at.
+pub struct Planner {
+    /// Max number of moves to look at, including the current one
+    look_ahead: usize,
+    /// Max distance in mm the path may cut inside a corner taken at speed
+    junction_deviation: f64,
+}
+
+/// A move in the look-ahead window
+struct Segment {
+    dist: f64,
+    dir: [f64; 2],
+    velocity: f64,
+}
+
+impl Planner {
+    pub fn new(look_ahead: usize, junction_deviation: f64) -> Self {
+        Planner {
+            look_ahead,
+            junction_deviation,
+        }
+    }
+    pub fn get_look_ahead(&self) -> &usize {
+        &self.look_ahead
+    }
+    /// Max velocity through the corner between two moves
+    fn junction_velocity(
+        &self,
+        solver: &SCurveSolver,
+        dir_in: &[f64; 2],
+        dir_out: &[f64; 2],
+    ) -> f64 {
+        // cos of the angle between the reversed incoming direction and the outgoing one,
+        // -1 for a straight line, 1 for a full reversal
+        let cos_theta = -(dir_in[0] * dir_out[0] + dir_in[1] * dir_out[1]);
+        let sin_half_theta = ((1.0 - cos_theta.clamp(-1.0, 1.0)) / 2.0).sqrt();
+        if sin_half_theta >= 1.0 {
+            return f64::INFINITY;
+        }
+        (solver.get_max_acceleration() * self.junction_deviation * sin_half_theta
+            / (1.0 - sin_half_theta))
+            .sqrt()
+    }
+    /// Velocity to finish the move from `start` to the first target in `path` at
+    ///
+    /// entry: velocity the move starts at
+    /// path: target and coast velocity of this move and the moves that follow it
+    pub fn exit_velocity(
+        &self,
+        solver: &SCurveSolver,
+        start: &PositionMM,
+        entry: &f64,
+        path: &[(PositionMM, f64)],
+    ) -> f64 {
+        let mut segments: Vec<Segment> = Vec::with_capacity(self.look_ahead);
+        let mut from = *start;
+        for (target, velocity) in path.iter().take(self.look_ahead) {
+            let dist = from.dist(target);
+            if dist == 0.0 {
+                continue;
+            }
+            segments.push(Segment {
+                dist,
+                dir: from.get_direction(target),
+                velocity: *velocity,
+            });
+            from = *target;
+        }
+        if segments.is_empty() {
+            return 0.0;
+        }
+        // exit velocity of each segment, stopping at the end of the window
+        let mut limits = vec![0.0; segments.len()];
+        for k in (0..segments.len() - 1).rev() {
+            let (current, next) = (&segments[k], &segments[k + 1]);
+            limits[k] = self
+                .junction_velocity(solver, &current.dir, &next.dir)
+                .min(current.velocity)
+                .min(next.velocity)
+                .min(solver.reachable_velocity(&limits[k + 1], &next.dist));
+        }
+        limits[0].min(solver.reachable_velocity(entry, &segments[0].dist))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::physical::Physical;
+
+    fn solver() -> SCurveSolver {
+        SCurveSolver::new(&Physical::default(), 100.0, 10000.0)
+    }
+
+    /// Exit velocity of the first of the moves through `points`, starting from rest
+    fn plan(solver: &SCurveSolver, points: &[[f64; 2]]) -> f64 {
+        let velocity = solver.reachable_velocity(&0.0, &f64::INFINITY);
+        let path: Vec<(PositionMM, f64)> = points[1..]
+            .iter()
+            .map(|xy| (PositionMM::new(*xy), velocity))
+            .collect();
+        Planner::new(8, 0.01).exit_velocity(solver, &PositionMM::new(points[0]), &0.0, &path)
+    }
+
+    #[test]
+    fn collinear_moves_keep_full_speed() {
+        let solver = solver();
+        let m_v = solver.reachable_velocity(&0.0, &f64::INFINITY);
+        let exit = plan(
+            &solver,
+            &[[0.0, 0.0], [50.0, 0.0], [100.0, 0.0], [150.0, 0.0]],
+        );
+        assert_eq!(exit, m_v);
+    }
+
+    #[test]
+    fn right_angle_is_taken_at_the_junction_velocity() {
+        let solver = solver();
+        let exit = plan(&solver, &[[0.0, 0.0], [50.0, 0.0], [50.0, 50.0]]);
+        // sin(45°) / (1 - sin(45°)) = 1 + sqrt(2)
+        let expected = (100.0 * 0.01 * (1.0 + 2.0_f64.sqrt())).sqrt();
+        assert!((exit - expected).abs() < 1e-9, "{exit} != {expected}");
+        assert!(exit < solver.reachable_velocity(&0.0, &f64::INFINITY));
+    }
+
+    #[test]
+    fn short_last_move_caps_the_junction_before_it() {
+        let solver = solver();
+        let exit = plan(&solver, &[[0.0, 0.0], [50.0, 0.0], [50.01, 0.0]]);
+        // only as fast as can still be stopped in the 0.01 mm that follow
+        let expected = solver.reachable_velocity(&0.0, &0.01);
+        assert!((exit - expected).abs() < 1e-9, "{exit} != {expected}");
+        assert!(exit < solver.reachable_velocity(&0.0, &f64::INFINITY));
+    }
+}
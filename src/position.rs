@@ -1,10 +1,10 @@
-use std::{fmt::Display, ops::Index};
+use std::{fmt::Display, ops::Index, str::FromStr};
 
 use nalgebra::Point2;
 
 use crate::{motor::StepInstruction, physical::Physical};
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 pub struct PositionMM {
     xy: [f64; 2],
 }
@@ -72,6 +72,26 @@ impl Display for PositionMM {
     }
 }
 
+/// Parse "x,y" in mm
+impl FromStr for PositionMM {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some(xy_s) = s.trim().split_once(',') else {
+            return Err("Did not get expected format");
+        };
+        let xy_s = [xy_s.0, xy_s.1];
+        let mut xy_f: [f64; 2] = [0.0, 0.0];
+        for (s, f) in xy_s.iter().zip(xy_f.iter_mut()) {
+            match s.trim().parse::<f64>() {
+                Ok(pf) => *f = pf,
+                Err(_) => return Err("Failed to parse"),
+            }
+        }
+        Ok(PositionMM::new(xy_f))
+    }
+}
+
 impl Index<usize> for PositionMM {
     type Output = f64;
     fn index(&self, index: usize) -> &Self::Output {
@@ -91,9 +111,10 @@ impl From<Point2<f64>> for PositionMM {
     }
 }
 
+/// Cord lengths in steps, which are only valid once checked with `Physical::check_step`
 #[derive(Default, Clone, Copy)]
 pub struct PositionStep {
-    rr: [usize; 2],
+    rr: [i64; 2],
 }
 
 impl Display for PositionStep {
@@ -103,10 +124,10 @@ impl Display for PositionStep {
 }
 
 impl PositionStep {
-    pub fn new(rr: [usize; 2]) -> Self {
+    pub fn new(rr: [i64; 2]) -> Self {
         PositionStep { rr }
     }
-    pub fn iter(&self) -> impl Iterator<Item = &usize> {
+    pub fn iter(&self) -> impl Iterator<Item = &i64> {
         self.rr.iter()
     }
     pub fn step(&mut self, index: usize, instruction: &StepInstruction) {
@@ -121,12 +142,12 @@ impl PositionStep {
         }
     }
     pub fn from_position_step_float(rr: &PositionStepFloat) -> Self {
-        PositionStep::new(rr.rr.map(|r| r.round() as usize))
+        PositionStep::new(rr.rr.map(|r| r.round() as i64))
     }
 }
 
 impl Index<usize> for PositionStep {
-    type Output = usize;
+    type Output = i64;
     fn index(&self, index: usize) -> &Self::Output {
         &self.rr[index]
     }
@@ -175,17 +196,25 @@ impl Position {
     pub fn new(mm: PositionMM, step: PositionStep) -> Self {
         Position { mm, step }
     }
+    /// Pen position from cord lengths, the intersection of a circle around each motor
+    ///
+    /// Of the two intersections the one below the line between the motors is used.
     pub fn from_step(step: PositionStep, physical: &Physical) -> Self {
         let stepf: PositionStepFloat = PositionStepFloat::from_position_step(&step, physical);
-        let r_m0 = stepf[0];
-        let r_m1 = stepf[1];
-        // solved for let motor_pos = [mm([0.0, 368.8]), mm([297.0, 368.8])];
-        let x = 0.00168350168350168 * r_m0.powi(2) - 0.00168350168350168 * r_m1.powi(2) + 148.5;
-        let pos_m0: &PositionMM = physical.get_motor_position(0);
-        let x_m0: f64 = pos_m0[0];
-        let y_m0: f64 = pos_m0[1];
-        let y = y_m0 - (r_m0.powi(2) - (x - x_m0).powi(2)).sqrt();
-        let mm = PositionMM::new([x, y]);
+        let (r_m0, r_m1) = (stepf[0], stepf[1]);
+        let pos_m0 = physical.get_motor_position(0);
+        let pos_m1 = physical.get_motor_position(1);
+        let d = pos_m0.dist(pos_m1);
+        // unit vector from motor 0 to motor 1 and its normal pointing away from the pen
+        let along = pos_m0.get_direction(pos_m1);
+        let normal = [-along[1], along[0]];
+        // distance along the motor line to the point nearest the pen
+        let a = (r_m0.powi(2) - r_m1.powi(2) + d.powi(2)) / (2.0 * d);
+        let h = (r_m0.powi(2) - a.powi(2)).max(0.0).sqrt();
+        let mm = PositionMM::new([
+            pos_m0[0] + a * along[0] - h * normal[0],
+            pos_m0[1] + a * along[1] - h * normal[1],
+        ]);
         Position::new(mm, step)
     }
     pub fn from_mm(mm: PositionMM, physical: &Physical) -> Self {
@@ -195,7 +224,7 @@ impl Position {
     pub fn get_step(&self) -> &PositionStep {
         &self.step
     }
-    pub fn iter_step(&self) -> impl Iterator<Item = &usize> {
+    pub fn iter_step(&self) -> impl Iterator<Item = &i64> {
         self.step.iter()
     }
     pub fn very_close_to(&self, other: &PositionMM, physical: &Physical) -> bool {
@@ -218,3 +247,52 @@ impl From<Position> for PositionStep {
         value.step
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::MachineProfile;
+
+    fn physical(left_motor: [f64; 2], right_motor: [f64; 2]) -> Physical {
+        let profile: MachineProfile = toml::from_str(&format!(
+            "left_motor = {left_motor:?}\nright_motor = {right_motor:?}"
+        ))
+        .unwrap();
+        Physical::new(&profile)
+    }
+
+    #[test]
+    fn from_step_inverts_cord_lengths() {
+        let machines = [
+            physical([0.0, 368.8], [297.0, 368.8]),
+            physical([-100.0, 500.0], [400.0, 500.0]),
+            // anchors at unequal heights
+            physical([0.0, 400.0], [297.0, 350.0]),
+            physical([0.0, 345.0], [297.0, 390.0]),
+        ];
+        // corners and middle of the default paper, and the home position
+        let points = [
+            [45.0, 80.0],
+            [250.0, 80.0],
+            [45.0, 338.0],
+            [250.0, 338.0],
+            [148.5, 200.0],
+            [148.5, 300.0],
+        ];
+        for physical in &machines {
+            for xy in points {
+                let mm = PositionMM::new(xy);
+                let step = physical.get_motor_dist(&mm);
+                let position = Position::from_step(step, physical);
+                // the cords of the position found are exactly the ones asked for
+                let cords = physical.get_motor_dist_float(&position.mm);
+                for (cord, r) in cords.iter().zip(step.iter()) {
+                    assert!((cord - *r as f64).abs() < 1e-6, "{physical} {mm}");
+                }
+                // and going back to whole steps lands on the same ones
+                let back = physical.get_motor_dist(&position.mm);
+                assert_eq!([back[0], back[1]], [step[0], step[1]], "{physical} {mm}");
+            }
+        }
+    }
+}
@@ -1,6 +1,7 @@
-use std::time::{Duration, Instant};
+use std::time::Duration;
 
 use crate::{
+    clock::Clock,
     motor::StepInstruction,
     position::{Position, PositionStepFloat},
 };
@@ -10,26 +11,22 @@ pub enum Prediction {
     MoveMotors([StepInstruction; 2]),
 }
 
+#[derive(Default)]
 pub struct Predictor {
-    last_instant: Instant,
+    last_time: f64,
     last_remainder: [f64; 2],
 }
 
-impl Default for Predictor {
-    fn default() -> Self {
+impl Predictor {
+    pub fn new(clock: &dyn Clock) -> Self {
         Predictor {
-            last_instant: Instant::now(),
-            last_remainder: [f64::default(); 2],
+            last_time: clock.now(),
+            ..Predictor::default()
         }
     }
-}
-
-impl Predictor {
-    pub fn new() -> Self {
-        Predictor::default()
-    }
     pub fn predict(
         &mut self,
+        clock: &dyn Clock,
         current_position: &Position,
         desired: &PositionStepFloat,
     ) -> Prediction {
@@ -59,7 +56,7 @@ impl Predictor {
         //         .iter()
         //         .zip(self.last_remainder.iter())
         //         .map(|(r, lr)| r - lr);
-        //     let time_diff = self.last_instant.elapsed().as_secs_f64();
+        //     let time_diff = clock.now() - self.last_time;
         //     let m = remainder_diff.map(|rd| rd / time_diff);
         //     // r = m dt + r0
         //     // dt = (sign(m) - r0)/m
@@ -75,7 +72,7 @@ impl Predictor {
         // info!("wait_time: {}", wait_time);
         // wait_time = wait_time.min(0.01);
         let wait_time = 0.0;
-        self.last_instant = Instant::now();
+        self.last_time = clock.now();
         self.last_remainder = remainders;
         if move_now {
             return Prediction::MoveMotors(instructions);
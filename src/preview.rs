@@ -0,0 +1,137 @@
+use std::path::Path;
+
+use log::info;
+
+use crate::{
+    gcode::{AxisLimit, PlotterInstruction, PlotterProgram},
+    physical::Physical,
+    position::PositionMM,
+    render::SvgCanvas,
+};
+
+/// Resolution of a png preview
+pub const DEFAULT_PX_PER_MM: f64 = 4.0;
+
+/// Distinct colors along the instruction order gradient
+const GRADIENT_STEPS: usize = 64;
+
+const PEN_STYLE: &str = "stroke:#000000;stroke-width:0.3";
+const TRAVEL_STYLE: &str =
+    "stroke:#6495ed;stroke-width:0.2;stroke-opacity:0.5;stroke-dasharray:1,1";
+
+/// Draw a program's moves over the machine's drawable area and the paper, without running
+/// it, to an svg or to a png if `output` ends in .png
+///
+/// travel: draw pen up moves as well, faintly
+/// gradient: color pen down moves from blue at the start of the program to red at the end
+/// px_per_mm: resolution of a png
+pub fn preview(
+    program: &PlotterProgram,
+    physical: &Physical,
+    paper: Option<&[AxisLimit; 2]>,
+    travel: &bool,
+    gradient: &bool,
+    px_per_mm: &f64,
+    output: &Path,
+) -> Result<(), &'static str> {
+    let x_limits = [
+        *physical.get_motor_position(0).x(),
+        *physical.get_motor_position(1).x(),
+    ];
+    let y_limits = [0.0, *physical.get_motor_position(0).y()];
+    let mut canvas = SvgCanvas::new(x_limits, y_limits);
+    canvas.rect(
+        physical.get_x_limits(),
+        &physical.get_y_limits(),
+        "stroke:#c0c0c0;stroke-width:0.3",
+    );
+    if let Some([x_limit, y_limit]) = paper {
+        canvas.rect(
+            x_limit.get(),
+            y_limit.get(),
+            "stroke:#2e8b57;stroke-width:0.3;stroke-dasharray:3,1",
+        );
+    }
+
+    let instructions = program.get_instructions();
+    let mut pen_down = false;
+    let mut last: Option<PositionMM> = None;
+    let [mut drawn, mut travelled] = [0.0, 0.0];
+    // consecutive moves drawn in the same style
+    let mut run: Vec<PositionMM> = Vec::new();
+    let mut run_style = String::new();
+    for (i, instruction) in instructions.iter().enumerate() {
+        match instruction {
+            PlotterInstruction::Move { target, .. } => {
+                let Some(from) = last.replace(*target) else {
+                    continue;
+                };
+                if pen_down {
+                    drawn += from.dist(target);
+                } else {
+                    travelled += from.dist(target);
+                }
+                let style = match (pen_down, gradient, travel) {
+                    (true, true, _) => {
+                        let t = (i * GRADIENT_STEPS / instructions.len()) as f64;
+                        format!(
+                            "stroke:{};stroke-width:0.3",
+                            gradient_color(t / (GRADIENT_STEPS - 1) as f64)
+                        )
+                    }
+                    (true, false, _) => String::from(PEN_STYLE),
+                    (false, _, true) => String::from(TRAVEL_STYLE),
+                    (false, _, false) => String::new(),
+                };
+                if style != run_style || run.is_empty() {
+                    canvas.polyline(&run, &run_style);
+                    run = vec![from];
+                    run_style = style;
+                }
+                if !run_style.is_empty() {
+                    run.push(*target);
+                }
+            }
+            PlotterInstruction::PenUp | PlotterInstruction::ChangePen(_) => pen_down = false,
+            PlotterInstruction::PenDown => pen_down = true,
+            PlotterInstruction::Home => {
+                // homing ends at home, but not along any line that can be drawn
+                canvas.polyline(&run, &run_style);
+                run.clear();
+                last = Some(*physical.get_home());
+            }
+            PlotterInstruction::Comment(_) => {}
+        }
+    }
+    canvas.polyline(&run, &run_style);
+    info!("preview draws {drawn:.0} mm with the pen down and {travelled:.0} mm with it up");
+
+    let png = output
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("png"));
+    if png {
+        canvas.save_png(output, px_per_mm)?;
+    } else {
+        canvas.save(output)?;
+    }
+    info!("wrote {}", output.display());
+    Ok(())
+}
+
+/// Color `t` of the way from blue to red, round the hue circle through green
+fn gradient_color(t: f64) -> String {
+    let hue = (1.0 - t.clamp(0.0, 1.0)) * 240.0;
+    // hsl to rgb at full saturation and a lightness a little under half, to show on white
+    let [lightness, a] = [0.45, 0.45];
+    let channel = |n: f64| {
+        let k = (n + hue / 30.0) % 12.0;
+        let value = lightness - a * (k - 3.0).min(9.0 - k).clamp(-1.0, 1.0);
+        (value * 255.0).round() as u8
+    };
+    format!(
+        "#{:02x}{:02x}{:02x}",
+        channel(0.0),
+        channel(8.0),
+        channel(4.0)
+    )
+}
@@ -0,0 +1,243 @@
+use std::{fs, path::Path, time::Duration};
+
+use log::error;
+use serde::Deserialize;
+
+/// Geometry, gearing and motion limits of one machine
+///
+/// Every field is optional in a profile file and falls back to the default machine.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MachineProfile {
+    /// Position in mm of the left motor's spool
+    left_motor: [f64; 2],
+    /// Position in mm of the right motor's spool
+    right_motor: [f64; 2],
+    /// Radius in mm the cord winds on to
+    spool_radius: f64,
+    /// Teeth of the driven and driving gear of each stage between motor and spool
+    gear_stages: Vec<[f64; 2]>,
+    /// Whole steps per revolution of the motor shaft
+    motor_steps_per_revolution: f64,
+    max_rpm: f64,
+    /// Drawable x range in mm
+    x_limits: [f64; 2],
+    /// Drawable y range in mm, before the y offset
+    y_limits: [f64; 2],
+    /// Offset in mm added to paper y limits
+    y_offset: f64,
+    /// Pen position in mm when both endstops have just closed
+    home: [f64; 2],
+    /// Speed in mm/s to reel cords in at while homing
+    homing_velocity: f64,
+    /// mm/s^2
+    max_acceleration: f64,
+    /// mm/s^3
+    max_jerk: f64,
+    /// Speed in mm/s of G0 travel moves, defaults to the max velocity
+    rapid_velocity: Option<f64>,
+    /// Number of moves the planner looks ahead over
+    look_ahead: usize,
+    /// Max distance in mm the path may cut inside a corner taken at speed
+    junction_deviation: f64,
+    /// Servo that lifts the pen, the operator is asked to move the pen when there is none
+    pen_lift: Option<ServoProfile>,
+}
+
+/// Hobby servo that lifts the pen
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServoProfile {
+    /// BCM number of the pin driving the servo signal
+    pin: u8,
+    /// Degrees with the pen lifted
+    up_angle: f64,
+    /// Degrees with the pen on the paper
+    down_angle: f64,
+    /// Seconds to wait after lifting the pen
+    up_settle_seconds: f64,
+    /// Seconds to wait after lowering the pen
+    down_settle_seconds: f64,
+    /// Pulse width in microseconds at 0 degrees
+    min_pulse_us: f64,
+    /// Pulse width in microseconds at 180 degrees
+    max_pulse_us: f64,
+}
+
+impl Default for ServoProfile {
+    fn default() -> Self {
+        ServoProfile {
+            pin: 18,
+            up_angle: 90.0,
+            down_angle: 30.0,
+            up_settle_seconds: 0.3,
+            down_settle_seconds: 0.5,
+            min_pulse_us: 1000.0,
+            max_pulse_us: 2000.0,
+        }
+    }
+}
+
+impl ServoProfile {
+    /// Time between pulses
+    pub const PERIOD: Duration = Duration::from_millis(20);
+
+    fn validate(&self) -> Result<(), &'static str> {
+        let angles = [self.up_angle, self.down_angle];
+        if angles.iter().any(|a| !(0.0..=180.0).contains(a)) {
+            return Err("Servo angles must be between 0 and 180 degrees");
+        }
+        if self.up_settle_seconds < 0.0 || self.down_settle_seconds < 0.0 {
+            return Err("Servo settle time can not be negative");
+        }
+        if self.min_pulse_us <= 0.0 || self.min_pulse_us >= self.max_pulse_us {
+            return Err("Servo pulse widths must be positive and given low then high");
+        }
+        Ok(())
+    }
+    /// Pulse width that turns the servo to an angle in degrees
+    pub fn pulse_width(&self, angle: &f64) -> Duration {
+        let us = self.min_pulse_us + angle / 180.0 * (self.max_pulse_us - self.min_pulse_us);
+        Duration::from_secs_f64(us * 1e-6)
+    }
+    pub fn get_pin(&self) -> &u8 {
+        &self.pin
+    }
+    pub fn get_up_angle(&self) -> &f64 {
+        &self.up_angle
+    }
+    pub fn get_down_angle(&self) -> &f64 {
+        &self.down_angle
+    }
+    pub fn get_up_settle_seconds(&self) -> &f64 {
+        &self.up_settle_seconds
+    }
+    pub fn get_down_settle_seconds(&self) -> &f64 {
+        &self.down_settle_seconds
+    }
+}
+
+impl Default for MachineProfile {
+    fn default() -> Self {
+        MachineProfile {
+            left_motor: [0.0, 368.8],
+            right_motor: [297.0, 368.8],
+            spool_radius: 5.75,
+            gear_stages: vec![[59.0, 17.0], [59.0, 17.0]],
+            motor_steps_per_revolution: 100.0,
+            max_rpm: 100.0,
+            x_limits: [45.0, 250.0],
+            y_limits: [70.0, 328.0],
+            y_offset: 10.0,
+            home: [148.5, 300.0],
+            homing_velocity: 2.0,
+            max_acceleration: 1e4,
+            max_jerk: 1e9,
+            rapid_velocity: None,
+            look_ahead: 32,
+            junction_deviation: 0.01,
+            pen_lift: None,
+        }
+    }
+}
+
+impl MachineProfile {
+    /// Read a toml machine profile
+    pub fn load(path: &Path) -> Result<Self, &'static str> {
+        let text = fs::read_to_string(path).map_err(|e| {
+            error!("{}: {e}", path.display());
+            "Failed to read machine profile"
+        })?;
+        let profile: MachineProfile = toml::from_str(&text).map_err(|e| {
+            error!("{}: {e}", path.display());
+            "Failed to parse machine profile"
+        })?;
+        profile.validate()?;
+        Ok(profile)
+    }
+    fn validate(&self) -> Result<(), &'static str> {
+        let positive = [
+            self.spool_radius,
+            self.motor_steps_per_revolution,
+            self.max_rpm,
+            self.max_acceleration,
+            self.max_jerk,
+            self.junction_deviation,
+            self.homing_velocity,
+        ];
+        if positive.iter().any(|v| *v <= 0.0) {
+            return Err("Machine profile values must be positive");
+        }
+        if self.gear_stages.iter().flatten().any(|teeth| *teeth <= 0.0) {
+            return Err("Gear teeth must be positive");
+        }
+        if self.left_motor[0] >= self.right_motor[0] {
+            return Err("Left motor must be left of the right motor");
+        }
+        if self.x_limits[0] >= self.x_limits[1] || self.y_limits[0] >= self.y_limits[1] {
+            return Err("Limits must be given low then high");
+        }
+        if self.look_ahead == 0 {
+            return Err("Look ahead must be at least one move");
+        }
+        if matches!(self.rapid_velocity, Some(v) if v <= 0.0) {
+            return Err("Rapid velocity must be positive");
+        }
+        if let Some(servo) = &self.pen_lift {
+            servo.validate()?;
+        }
+        Ok(())
+    }
+    pub fn get_motor_positions(&self) -> [[f64; 2]; 2] {
+        [self.left_motor, self.right_motor]
+    }
+    pub fn get_spool_radius(&self) -> &f64 {
+        &self.spool_radius
+    }
+    /// Motor revolutions per spool revolution
+    pub fn get_gear_ratio(&self) -> f64 {
+        self.gear_stages
+            .iter()
+            .map(|[driven, driving]| driven / driving)
+            .product()
+    }
+    pub fn get_motor_steps_per_revolution(&self) -> &f64 {
+        &self.motor_steps_per_revolution
+    }
+    pub fn get_max_rpm(&self) -> &f64 {
+        &self.max_rpm
+    }
+    pub fn get_x_limits(&self) -> &[f64; 2] {
+        &self.x_limits
+    }
+    pub fn get_y_limits(&self) -> &[f64; 2] {
+        &self.y_limits
+    }
+    pub fn get_y_offset(&self) -> &f64 {
+        &self.y_offset
+    }
+    pub fn get_home(&self) -> &[f64; 2] {
+        &self.home
+    }
+    pub fn get_homing_velocity(&self) -> &f64 {
+        &self.homing_velocity
+    }
+    pub fn get_max_acceleration(&self) -> &f64 {
+        &self.max_acceleration
+    }
+    pub fn get_max_jerk(&self) -> &f64 {
+        &self.max_jerk
+    }
+    pub fn get_rapid_velocity(&self) -> &Option<f64> {
+        &self.rapid_velocity
+    }
+    pub fn get_look_ahead(&self) -> &usize {
+        &self.look_ahead
+    }
+    pub fn get_junction_deviation(&self) -> &f64 {
+        &self.junction_deviation
+    }
+    pub fn get_pen_lift(&self) -> &Option<ServoProfile> {
+        &self.pen_lift
+    }
+}
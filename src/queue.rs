@@ -0,0 +1,123 @@
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    controller::Fit,
+    draw::Pattern,
+    gcode::PaperLimits,
+    state::{load_toml, save_toml},
+};
+
+/// Where a job's program comes from
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobSource {
+    File(PathBuf),
+    Pattern(Pattern),
+}
+
+/// One plot in a batch
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Job {
+    source: JobSource,
+    /// Paper for this job, otherwise the paper limits already set are used
+    paper: Option<PaperLimits>,
+    fit: Fit,
+}
+
+impl Job {
+    pub fn new(source: JobSource, paper: Option<PaperLimits>, fit: Fit) -> Self {
+        Job { source, paper, fit }
+    }
+    pub fn get_source(&self) -> &JobSource {
+        &self.source
+    }
+    pub fn get_paper(&self) -> &Option<PaperLimits> {
+        &self.paper
+    }
+    pub fn get_fit(&self) -> &Fit {
+        &self.fit
+    }
+}
+
+impl Display for Job {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.source {
+            JobSource::File(path) => write!(f, "{}", path.display())?,
+            JobSource::Pattern(pattern) => write!(f, "{pattern}")?,
+        }
+        if let Some(paper) = &self.paper {
+            write!(f, ", paper {paper}")?;
+        }
+        write!(f, ", fit {:?}", self.fit)
+    }
+}
+
+/// Jobs waiting to run, first one next. Finished jobs are removed.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JobQueue {
+    jobs: Vec<Job>,
+}
+
+impl JobQueue {
+    /// Read a queue file, which is an empty queue if there is none yet
+    pub fn load(path: &Path) -> Result<Self, &'static str> {
+        Ok(load_toml(path)?.unwrap_or_default())
+    }
+    pub fn save(&self, path: &Path) -> Result<(), &'static str> {
+        save_toml(self, path)
+    }
+    pub fn front(&self) -> Option<&Job> {
+        self.jobs.first()
+    }
+    pub fn push(&mut self, job: Job) {
+        self.jobs.push(job);
+    }
+    /// Take the first job off, once it has finished
+    pub fn pop_front(&mut self) {
+        if !self.jobs.is_empty() {
+            self.jobs.remove(0);
+        }
+    }
+    pub fn remove(&mut self, index: &usize) -> Result<Job, &'static str> {
+        if *index >= self.jobs.len() {
+            return Err("No such job");
+        }
+        Ok(self.jobs.remove(*index))
+    }
+    /// Swap a job with the one before it
+    pub fn move_up(&mut self, index: &usize) -> Result<(), &'static str> {
+        if *index == 0 || *index >= self.jobs.len() {
+            return Err("Can not move that job up");
+        }
+        self.jobs.swap(*index - 1, *index);
+        Ok(())
+    }
+    pub fn clear(&mut self) {
+        self.jobs.clear();
+    }
+    pub fn is_empty(&self) -> bool {
+        self.jobs.is_empty()
+    }
+}
+
+impl Display for JobQueue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.jobs.is_empty() {
+            return write!(f, "no jobs queued");
+        }
+        for (index, job) in self.jobs.iter().enumerate() {
+            if index > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{index}: {job}")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,100 @@
+use std::{fmt::Write, fs, path::Path};
+
+use resvg::tiny_skia::{Color, Pixmap, Transform};
+use usvg::{Options, Tree};
+
+use crate::position::PositionMM;
+
+/// Minimal SVG writer working in machine mm, where y points up
+pub struct SvgCanvas {
+    x_limits: [f64; 2],
+    y_limits: [f64; 2],
+    body: String,
+}
+
+impl SvgCanvas {
+    pub fn new(x_limits: [f64; 2], y_limits: [f64; 2]) -> Self {
+        SvgCanvas {
+            x_limits,
+            y_limits,
+            body: String::new(),
+        }
+    }
+    /// Flip y so the drawing is not upside down
+    fn point(&self, mm: &PositionMM) -> (f64, f64) {
+        (*mm.x(), self.y_limits[1] - mm.y())
+    }
+    pub fn polyline(&mut self, points: &[PositionMM], style: &str) {
+        if points.len() < 2 {
+            return;
+        }
+        let mut coords = String::new();
+        for mm in points {
+            let (x, y) = self.point(mm);
+            write!(coords, "{x:.3},{y:.3} ").unwrap();
+        }
+        writeln!(
+            self.body,
+            "<polyline points=\"{}\" style=\"fill:none;{style}\"/>",
+            coords.trim_end()
+        )
+        .unwrap();
+    }
+    pub fn rect(&mut self, x_limits: &[f64; 2], y_limits: &[f64; 2], style: &str) {
+        let (x, y) = self.point(&PositionMM::new([x_limits[0], y_limits[1]]));
+        writeln!(
+            self.body,
+            "<rect x=\"{x:.3}\" y=\"{y:.3}\" width=\"{:.3}\" height=\"{:.3}\" style=\"fill:none;{style}\"/>",
+            x_limits[1] - x_limits[0],
+            y_limits[1] - y_limits[0]
+        )
+        .unwrap();
+    }
+    pub fn text(&mut self, mm: &PositionMM, text: &str) {
+        let (x, y) = self.point(mm);
+        writeln!(
+            self.body,
+            "<text x=\"{x:.3}\" y=\"{y:.3}\" style=\"font-size:5px;font-family:sans-serif\">{text}</text>"
+        )
+        .unwrap();
+    }
+    fn to_svg(&self) -> String {
+        let width = self.x_limits[1] - self.x_limits[0];
+        let height = self.y_limits[1] - self.y_limits[0];
+        format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width}mm\" height=\"{height}mm\" viewBox=\"{} 0 {width} {height}\">\n{}</svg>\n",
+            self.x_limits[0], self.body
+        )
+    }
+    pub fn save(&self, path: &Path) -> Result<(), &'static str> {
+        fs::write(path, self.to_svg()).map_err(|e| {
+            log::error!("{e}");
+            "Failed to write svg"
+        })
+    }
+    /// Rasterize onto white at `px_per_mm` and write a png. Text is left out.
+    pub fn save_png(&self, path: &Path, px_per_mm: &f64) -> Result<(), &'static str> {
+        let tree = Tree::from_str(&self.to_svg(), &Options::default()).map_err(|e| {
+            log::error!("{e}");
+            "Failed to render svg"
+        })?;
+        // the tree is sized in css pixels, 96 to the inch
+        let scale = (px_per_mm * 25.4 / 96.0) as f32;
+        let size = tree
+            .size()
+            .to_int_size()
+            .scale_by(scale)
+            .ok_or("Png would be empty")?;
+        let mut pixmap = Pixmap::new(size.width(), size.height()).ok_or("Png would be empty")?;
+        pixmap.fill(Color::WHITE);
+        resvg::render(
+            &tree,
+            Transform::from_scale(scale, scale),
+            &mut pixmap.as_mut(),
+        );
+        pixmap.save_png(path).map_err(|e| {
+            log::error!("{}: {e}", path.display());
+            "Failed to write png"
+        })
+    }
+}
@@ -1,125 +1,168 @@
-use std::{fmt::Display, time::Instant};
+use std::fmt::Display;
 
 use crate::{
+    clock::Clock,
     controller::MoveStatus,
     physical::Physical,
     position::{PositionMM, PositionStepFloat},
 };
 
-/// Solve for 7-stage s-curve
+/// Solve for 7-stage s-curves that may start and end moving
 ///
 /// stage_0: max_jerk until max_acceleration
-/// stage_1: max_acceleration until almost max_velocity
-/// stage_2: negative max_jerk until zero acceleration and max_velocity
-/// stage_3: coast at max_velocity
+/// stage_1: max_acceleration until almost the coast velocity
+/// stage_2: negative max_jerk until zero acceleration and the coast velocity
+/// stage_3: coast
 /// stage_4: negative max_jerk until negative max_acceleration
-/// stage_5: negative max_acceleration until nearly zero velocity
-/// stage_6: max_jerk until zero acceleration and zero velocity
+/// stage_5: negative max_acceleration until nearly the exit velocity
+/// stage_6: max_jerk until zero acceleration and the exit velocity
 pub struct SCurveSolver {
     /// Max velocity
-    // m_v: f64,
+    m_v: f64,
     /// Max acceleration
     m_a: f64,
     /// Max jerk
     m_j: f64,
-    /// Time of max jerk stages (0, 2, 4, and 6)
-    t_j0: f64,
-    /// Time of max acceleration stages (1 and 5)
-    t_v1: f64,
-    /// The minimum distance in mm of a truncated coast (full) s-curve
-    min_dist_truncated_coast: f64,
-    /// The minimum distance in mm of a truncated max_accerleration (mid) s-curve
-    min_dist_truncated_max_acceleration: f64,
 }
 
 impl Display for SCurveSolver {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (t_j0, t_v1) = self.velocity_change_times(&self.m_v);
         write!(
             f,
-            "m_a: {}, m_j: {}, t_j0: {}, t_v1: {}, min_dist_full: {}, min_dist_mid: {}",
+            "m_v: {}, m_a: {}, m_j: {}, t_j0: {}, t_v1: {}, min_dist_full: {}",
+            self.m_v,
             self.m_a,
             self.m_j,
-            self.t_j0,
-            self.t_v1,
-            self.min_dist_truncated_coast,
-            self.min_dist_truncated_max_acceleration
+            t_j0,
+            t_v1,
+            2.0 * self.velocity_change_dist(&0.0, &self.m_v)
         )
     }
 }
 
+/// Largest value in lo..=hi that fits, given that lo does
+fn bisect(lo: f64, hi: f64, fits: impl Fn(f64) -> bool) -> f64 {
+    if fits(hi) {
+        return hi;
+    }
+    let (mut lo, mut hi) = (lo, hi);
+    for _ in 0..50 {
+        let mid = (lo + hi) / 2.0;
+        if fits(mid) {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    lo
+}
+
 impl SCurveSolver {
     /// m_a: Max acceleration
     /// m_j: Max jerk
     pub fn new(physical: &Physical, m_a: f64, m_j: f64) -> Self {
-        let m_v = *physical.get_max_velocity();
-        let t_j0 = (m_a / m_j).min((m_v * 2.0 / m_j).sqrt());
-        let t_v1 = (-m_j * t_j0.powi(2) + m_v) / m_a;
-        let min_dist_truncated_coast = -m_a * t_j0.powi(2) / 2.0
-            + m_j * t_j0.powi(3)
-            + m_j * t_j0.powi(2) * t_v1 / 2.0
-            + t_j0 * (m_a * t_v1 + m_j * t_j0.powi(2) / 2.0)
-            + t_j0 * (m_a * t_v1 + m_j * t_j0.powi(2))
-            + t_v1 * (m_a * t_v1 + m_j * t_j0.powi(2) / 2.0);
-        let min_dist_truncated_max_acceleration =
-            -m_a * t_j0.powi(2) / 2.0 + 5.0 * m_j * t_j0.powi(3) / 2.0;
-        assert!(min_dist_truncated_coast >= min_dist_truncated_max_acceleration);
         SCurveSolver {
-            // m_v,
+            m_v: *physical.get_max_velocity(),
             m_a,
             m_j,
-            t_j0,
-            t_v1,
-            min_dist_truncated_coast,
-            min_dist_truncated_max_acceleration,
         }
     }
-    pub fn solve_curve(&self, start: PositionMM, end: PositionMM) -> SCurve {
-        let dist = start.dist(&end);
-        if dist >= self.min_dist_truncated_coast {
-            self.solve_truncated_coast_curve(start, end)
-        } else if dist > self.min_dist_truncated_max_acceleration {
-            self.solve_truncated_max_acceleration_curve(start, end)
+    pub fn get_max_acceleration(&self) -> &f64 {
+        &self.m_a
+    }
+    /// Time of each max jerk stage and of the max acceleration stage needed to change
+    /// velocity by dv
+    fn velocity_change_times(&self, dv: &f64) -> (f64, f64) {
+        let dv = dv.abs();
+        if dv * self.m_j >= self.m_a.powi(2) {
+            (self.m_a / self.m_j, dv / self.m_a - self.m_a / self.m_j)
         } else {
-            self.solve_truncated_max_jerk_curve(start, end)
+            ((dv / self.m_j).sqrt(), 0.0)
         }
     }
-    fn solve_truncated_coast_curve(&self, start: PositionMM, end: PositionMM) -> SCurve {
-        let p = start.dist(&end);
-        let t_c3 = (self.m_a * self.t_j0.powi(2) / 2.0
-            - 2.0 * self.m_a * self.t_j0 * self.t_v1
-            - self.m_a * self.t_v1.powi(2)
-            - 5.0 * self.m_j * self.t_j0.powi(3) / 2.0
-            - self.m_j * self.t_j0.powi(2) * self.t_v1
-            + p)
-            / (self.m_a * self.t_v1 + self.m_j * self.t_j0.powi(2));
-        SCurve::new(start, end, self.t_j0, self.t_v1, t_c3, self)
+    /// Distance in mm covered while changing velocity from v0 to v1
+    pub fn velocity_change_dist(&self, v0: &f64, v1: &f64) -> f64 {
+        let (t_j, t_a) = self.velocity_change_times(&(v1 - v0));
+        // the velocity profile is symmetric, so the average is the midpoint
+        (v0 + v1) / 2.0 * (2.0 * t_j + t_a)
     }
-    fn solve_truncated_max_acceleration_curve(&self, start: PositionMM, end: PositionMM) -> SCurve {
-        let p = start.dist(&end);
-        let t_v1 = (-self.t_j0 * (2.0 * self.m_a + self.m_j * self.t_j0)
-            + (6.0 * self.m_a.powi(2) * self.t_j0.powi(2)
-                - 6.0 * self.m_a * self.m_j * self.t_j0.powi(3)
-                + 4.0 * self.m_a * p
-                + self.m_j.powi(2) * self.t_j0.powi(4))
-            .sqrt())
-            / (2.0 * self.m_a);
-        assert!(t_v1 > 0.0, "Need to add in second solution");
-        SCurve::new(start, end, self.t_j0, t_v1, 0.0, self)
+    /// Highest velocity, up to max velocity, that can be changed to from `velocity` (or back
+    /// again) within `dist` mm
+    pub fn reachable_velocity(&self, velocity: &f64, dist: &f64) -> f64 {
+        if *velocity >= self.m_v {
+            return *velocity;
+        }
+        bisect(*velocity, self.m_v, |v| {
+            self.velocity_change_dist(velocity, &v) <= *dist
+        })
     }
-    fn solve_truncated_max_jerk_curve(&self, start: PositionMM, end: PositionMM) -> SCurve {
-        let p = start.dist(&end);
-        let t_j0 = 2.0_f64.powf(2.0 / 3.0) * (p / self.m_j).powf(1.0 / 3.0) / 2.0;
-        SCurve::new(start, end, t_j0, 0.0, 0.0, self)
+    /// v_entry: velocity at start, must be able to reach v_exit within the move
+    /// v_exit: velocity at end
+    /// velocity: requested coast velocity in mm/s, capped at max velocity
+    /// t_start: clock time the move begins
+    pub fn solve_curve(
+        &self,
+        start: PositionMM,
+        end: PositionMM,
+        v_entry: &f64,
+        v_exit: &f64,
+        velocity: &f64,
+        t_start: f64,
+    ) -> SCurve {
+        let dist = start.dist(&end);
+        let v_min = v_entry.max(*v_exit);
+        let v_cap = velocity.min(self.m_v).max(v_min);
+        let change_dist =
+            |v: f64| self.velocity_change_dist(v_entry, &v) + self.velocity_change_dist(&v, v_exit);
+        let v_coast = bisect(v_min, v_cap, |v| change_dist(v) <= dist);
+        let (t_ja, t_aa) = self.velocity_change_times(&(v_coast - v_entry));
+        let (t_jd, t_ad) = self.velocity_change_times(&(v_coast - v_exit));
+        let t_c3 = if v_coast > 0.0 {
+            ((dist - change_dist(v_coast)) / v_coast).max(0.0)
+        } else {
+            0.0
+        };
+        SCurve::new(
+            start,
+            end,
+            t_start,
+            *v_entry,
+            [t_ja, t_aa, t_ja, t_c3, t_jd, t_ad, t_jd],
+            &self.m_j,
+        )
+    }
+    /// Slow from velocity to a stop in a straight line
+    ///
+    /// velocity: mm/s, must be positive
+    pub fn solve_stop(
+        &self,
+        start: PositionMM,
+        direction: &[f64; 2],
+        velocity: &f64,
+        t_start: f64,
+    ) -> SCurve {
+        let end = start.offset(&self.velocity_change_dist(velocity, &0.0), direction);
+        self.solve_curve(start, end, velocity, &0.0, velocity, t_start)
     }
 }
 
+#[derive(Default)]
 pub struct SCurve {
     start: PositionMM,
-    t_start: Instant,
+    t_start: f64,
+    /// End time of each stage since t_start
     t: [f64; 7],
-    a_j0: f64,
+    /// Jerk of each stage
+    j: [f64; 7],
+    /// Acceleration at the start of each stage
+    a: [f64; 7],
+    /// Velocity at the start of each stage
     v: [f64; 7],
+    /// Distance travelled at the start of each stage
     p: [f64; 7],
+    v_exit: f64,
+    p_end: f64,
     dir: [f64; 2],
 }
 
@@ -133,142 +176,171 @@ impl Display for SCurve {
         };
         write!(
             f,
-            "start: {}, t:[{}], a_j0: {}, v: [{}], p: [{}], dir: [{}]",
+            "start: {}, t:[{}], v: [{}], p: [{}], v_exit: {:.2}, dir: [{}]",
             self.start,
             print_arr(&self.t),
-            self.a_j0,
             print_arr(&self.v),
             print_arr(&self.p),
+            self.v_exit,
             print_arr(&self.dir),
         )
     }
 }
 
-impl Default for SCurve {
-    fn default() -> Self {
-        SCurve {
-            start: PositionMM::default(),
-            t_start: Instant::now(),
-            t: [f64::default(); 7],
-            a_j0: f64::default(),
-            v: [f64::default(); 7],
-            p: [f64::default(); 7],
-            dir: [f64::default(); 2],
-        }
-    }
-}
-
 impl SCurve {
+    /// durations: time spent in each stage
+    /// m_j: Max jerk
     pub fn new(
         start: PositionMM,
         end: PositionMM,
-        t_j0: f64,
-        t_v1: f64,
-        t_c3: f64,
-        solver: &SCurveSolver,
+        t_start: f64,
+        v_entry: f64,
+        durations: [f64; 7],
+        m_j: &f64,
     ) -> Self {
+        let j = [*m_j, 0.0, -m_j, 0.0, -m_j, 0.0, *m_j];
         let mut t = [0.0; 7];
-        t[0] = t_j0;
-        t[1] = t[0] + t_v1;
-        t[2] = t[1] + t_j0;
-        t[3] = t[2] + t_c3;
-        t[4] = t[3] + t_j0;
-        t[5] = t[4] + t_v1;
-        t[6] = t[5] + t_j0;
+        let mut a = [0.0; 7];
         let mut v = [0.0; 7];
         let mut p = [0.0; 7];
-        let a_j0 = solver.m_j * t_j0;
-        v[0] = solver.m_j * t_j0.powi(2) / 2.0;
-        p[0] = solver.m_j * t_j0.powi(3) / 6.0;
-        v[1] = v[0] + solver.m_a * t_v1;
-        p[1] = p[0] + v[0] * t_v1 + solver.m_a * t_v1.powi(2) / 2.0;
-        v[2] = v[1] + a_j0 * t_j0 - solver.m_j * t_j0.powi(2) / 2.0;
-        p[2] = p[1] + v[1] * t_j0 + a_j0 * t_j0.powi(2) / 2.0 - solver.m_j * t_j0.powi(3) / 6.0;
-        p[3] = p[2] + v[2] * t_c3;
-        let a_j4 = -solver.m_j * t_j0;
-        v[4] = v[2] - solver.m_j * t_j0.powi(2) / 2.0;
-        p[4] = p[3] + v[2] * t_j0 - solver.m_j * t_j0.powi(3) / 6.0;
-        v[5] = v[4] - solver.m_a * t_v1;
-        p[5] = p[4] + v[4] * t_v1 - solver.m_a * t_v1.powi(2) / 2.0;
-        p[6] = p[5] + v[5] * t_j0 + a_j4 * t_j0.powi(2) / 2.0 + solver.m_j * t_j0.powi(3) / 6.0;
+        let (mut t_end, mut a_end, mut v_end, mut p_end) = (0.0, 0.0, v_entry, 0.0);
+        for (stage, dt) in durations.iter().enumerate() {
+            a[stage] = a_end;
+            v[stage] = v_end;
+            p[stage] = p_end;
+            p_end += v_end * dt + a_end * dt.powi(2) / 2.0 + j[stage] * dt.powi(3) / 6.0;
+            v_end += a_end * dt + j[stage] * dt.powi(2) / 2.0;
+            a_end += j[stage] * dt;
+            t_end += dt;
+            t[stage] = t_end;
+        }
         let dir = start.get_direction(&end);
         SCurve {
             start,
-            t_start: Instant::now(),
+            t_start,
             t,
-            a_j0,
+            j,
+            a,
             v,
             p,
+            v_exit: v_end,
+            p_end,
             dir,
         }
     }
     /// Return if we are in the process of moving or not
-    pub fn get_move_status(&self) -> MoveStatus {
-        let elasped = self.t_start.elapsed().as_secs_f64();
-        if elasped >= self.t[self.t.len() - 1] {
+    pub fn get_move_status(&self, clock: &dyn Clock) -> MoveStatus {
+        if clock.now() >= self.end_time() {
             MoveStatus::Stopped
         } else {
             MoveStatus::Moving
         }
     }
-    /// Return the desired step of the motors
-    pub fn get_desired(&self, solver: &SCurveSolver, physical: &Physical) -> PositionStepFloat {
-        let elasped = self.t_start.elapsed().as_secs_f64();
-        // info!("elasped: {elasped}");
-        let p = if elasped < self.t[0] {
-            // info!("stage 0");
-            self.stage_0(elasped, solver)
-        } else if elasped < self.t[1] {
-            // info!("stage 1");
-            self.stage_1(elasped, solver)
-        } else if elasped < self.t[2] {
-            // info!("stage 2");
-            self.stage_2(elasped, solver)
-        } else if elasped < self.t[3] {
-            // info!("stage 3");
-            self.stage_3(elasped)
-        } else if elasped < self.t[4] {
-            // info!("stage 4");
-            self.stage_4(elasped, solver)
-        } else if elasped < self.t[5] {
-            // info!("stage 5");
-            self.stage_5(elasped, solver)
-        } else {
-            // info!("stage 6");
-            self.stage_6(elasped, solver)
-        };
-        // info!("p: {p}");
-        let dist = self.dir.iter().map(|d| d * p);
-        let mut desired = self.start.iter().zip(dist).map(|(&s, d)| s + d);
-        let desired = PositionMM::new([desired.next().unwrap(), desired.next().unwrap()]);
-        PositionStepFloat::from_mm(&desired, physical)
+    /// Clock time the move finishes
+    pub fn end_time(&self) -> f64 {
+        self.t_start + self.t[self.t.len() - 1]
     }
-
-    fn stage_0(&self, elasped: f64, solver: &SCurveSolver) -> f64 {
-        solver.m_j * elasped.powi(3) / 6.0
+    /// Velocity the move finishes at
+    pub fn get_exit_velocity(&self) -> &f64 {
+        &self.v_exit
     }
-    fn stage_1(&self, elasped: f64, solver: &SCurveSolver) -> f64 {
-        let t = elasped - self.t[0];
-        self.p[0] + self.v[0] * t + solver.m_a * t.powi(2) / 2.0
+    /// Unit vector the move travels along
+    pub fn get_direction(&self) -> &[f64; 2] {
+        &self.dir
     }
-    fn stage_2(&self, elasped: f64, solver: &SCurveSolver) -> f64 {
-        let t = elasped - self.t[1];
-        self.p[1] + self.v[1] * t + self.a_j0 * t.powi(2) / 2.0 - solver.m_j * t.powi(3) / 6.0
+    /// Distance travelled and velocity at the current time
+    fn state(&self, clock: &dyn Clock) -> (f64, f64) {
+        let elasped = clock.now() - self.t_start;
+        match self.t.iter().position(|&t| elasped < t) {
+            Some(stage) => {
+                let t = if stage == 0 {
+                    elasped
+                } else {
+                    elasped - self.t[stage - 1]
+                };
+                let p = self.p[stage]
+                    + self.v[stage] * t
+                    + self.a[stage] * t.powi(2) / 2.0
+                    + self.j[stage] * t.powi(3) / 6.0;
+                let v = self.v[stage] + self.a[stage] * t + self.j[stage] * t.powi(2) / 2.0;
+                (p, v)
+            }
+            None => (self.p_end, self.v_exit),
+        }
     }
-    fn stage_3(&self, elasped: f64) -> f64 {
-        let t = elasped - self.t[2];
-        self.p[2] + self.v[2] * t
+    /// Velocity in mm/s the move should be at now
+    pub fn get_velocity(&self, clock: &dyn Clock) -> f64 {
+        self.state(clock).1
     }
-    fn stage_4(&self, elasped: f64, solver: &SCurveSolver) -> f64 {
-        let t = elasped - self.t[3];
-        self.p[3] + self.v[2] * t - solver.m_j * t.powi(3) / 6.0
+    /// Position in mm the move should be at now
+    pub fn get_position(&self, clock: &dyn Clock) -> PositionMM {
+        let (p, _) = self.state(clock);
+        self.start.offset(&p, &self.dir)
     }
-    fn stage_5(&self, elasped: f64, solver: &SCurveSolver) -> f64 {
-        let t = elasped - self.t[4];
-        self.p[4] + self.v[4] * t - solver.m_a * t.powi(2) / 2.0
+    /// Return the desired step of the motors
+    pub fn get_desired(&self, clock: &dyn Clock, physical: &Physical) -> PositionStepFloat {
+        PositionStepFloat::from_mm(&self.get_position(clock), physical)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::VirtualClock;
+
+    /// Velocity at every tick of a move from rest to rest along x
+    fn sample_velocities(solver: &SCurveSolver, dist: f64, tick: f64) -> (Vec<f64>, PositionMM) {
+        let clock = VirtualClock::new(tick);
+        let start = PositionMM::new([100.0, 200.0]);
+        let end = PositionMM::new([100.0 + dist, 200.0]);
+        let curve = solver.solve_curve(start, end, &0.0, &0.0, &f64::INFINITY, clock.now());
+        let mut velocities = Vec::new();
+        while curve.get_move_status(&clock) == MoveStatus::Moving {
+            velocities.push(curve.get_velocity(&clock));
+            clock.tick();
+        }
+        (velocities, curve.get_position(&clock))
     }
-    fn stage_6(&self, elasped: f64, solver: &SCurveSolver) -> f64 {
-        let t = elasped - self.t[5];
-        self.p[5] + self.v[5] * t - solver.m_a * t.powi(2) / 2.0 + solver.m_j * t.powi(3) / 6.0
+
+    #[test]
+    fn s_curve_is_reproducible_and_within_limits() {
+        let physical = Physical::default();
+        let (m_a, m_j) = (100.0, 10000.0);
+        let solver = SCurveSolver::new(&physical, m_a, m_j);
+        let m_v = *physical.get_max_velocity();
+        let tick = 1e-4;
+        let (velocities, end) = sample_velocities(&solver, 20.0, tick);
+        assert_eq!(velocities, sample_velocities(&solver, 20.0, tick).0);
+        assert!((end.x() - 120.0).abs() < 1e-9 && (end.y() - 200.0).abs() < 1e-9);
+
+        // speeds up to the max velocity, coasts and slows down again, within the limits
+        assert!(velocities.iter().all(|v| *v >= -1e-9 && *v <= m_v + 1e-9));
+        let peak = velocities.iter().position(|v| *v >= m_v - 1e-9).unwrap();
+        let accelerations: Vec<f64> = velocities
+            .windows(2)
+            .map(|w| (w[1] - w[0]) / tick)
+            .collect();
+        assert!(accelerations.iter().all(|a| a.abs() <= m_a + 1e-6));
+        assert!(accelerations[..peak].iter().all(|a| *a >= -1e-6));
+        assert!(accelerations[peak..].iter().all(|a| *a <= 1e-6));
+        let coast = velocities
+            .iter()
+            .filter(|v| (*v - m_v).abs() < 1e-9)
+            .count() as f64
+            * tick;
+        assert!(coast > 0.0);
+        // the first stage only reaches max acceleration after m_a / m_j
+        let jerk_ticks = (m_a / m_j / tick) as usize;
+        assert!(accelerations[jerk_ticks / 2] < m_a);
+    }
+
+    #[test]
+    fn short_moves_do_not_reach_max_velocity() {
+        let physical = Physical::default();
+        let solver = SCurveSolver::new(&physical, 100.0, 10000.0);
+        let (velocities, end) = sample_velocities(&solver, 0.1, 1e-4);
+        let peak = velocities.iter().fold(0.0, |a: f64, v| a.max(*v));
+        assert!(peak < *physical.get_max_velocity());
+        assert!((end.x() - 100.1).abs() < 1e-9);
     }
 }
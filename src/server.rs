@@ -0,0 +1,291 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use clap::ValueEnum;
+use log::{error, info};
+use tiny_http::{Header, Method, Request, Response, StatusCode};
+use tungstenite::{handshake::derive_accept_key, protocol::Role, Message, WebSocket};
+
+use crate::{
+    controller::{ControlCommand, Controller, Fit, Status},
+    gcode::PaperLimits,
+    position::PositionMM,
+};
+
+/// How often live status is sent to websocket clients
+const STATUS_PERIOD: Duration = Duration::from_millis(250);
+
+/// Control page served at /
+const PAGE: &str = include_str!("server.html");
+
+/// Changes that need the controller, made while no program is running
+enum Job {
+    LoadProgram(PathBuf),
+    SetPosition(PositionMM),
+    SetPaper(PaperLimits),
+    Fit(Fit),
+    Home,
+    Start(usize),
+}
+
+type Reply = Sender<Result<(), &'static str>>;
+
+/// Http control page and api, with live status over a websocket at /ws
+///
+/// Requests are answered on their own threads. Pause, resume and abort go straight to the
+/// controller's control channel, everything else waits for `Server::pass` on the thread that
+/// owns the controller.
+pub struct Server {
+    jobs: Receiver<(Job, Reply)>,
+}
+
+impl Server {
+    /// address: "host:port" to listen on
+    /// upload_dir: where uploaded gcode files are written
+    pub fn start(
+        address: &str,
+        upload_dir: PathBuf,
+        controller: &Controller,
+    ) -> Result<Self, &'static str> {
+        let http = tiny_http::Server::http(address).map_err(|e| {
+            error!("{address}: {e}");
+            "Failed to start server"
+        })?;
+        fs::create_dir_all(&upload_dir).map_err(|e| {
+            error!("{}: {e}", upload_dir.display());
+            "Failed to create upload directory"
+        })?;
+        let (job_sender, jobs) = mpsc::channel();
+        let control = controller.control();
+        let status = controller.shared_status();
+        info!("serving on http://{address}");
+        thread::spawn(move || {
+            for request in http.incoming_requests() {
+                let job_sender = job_sender.clone();
+                let control = control.clone();
+                let status = status.clone();
+                let upload_dir = upload_dir.clone();
+                thread::spawn(move || handle(request, &job_sender, &control, status, &upload_dir));
+            }
+        });
+        Ok(Server { jobs })
+    }
+
+    /// Run one instruction of a running program, or wait a little for a job and do it
+    ///
+    /// Returns false once the server has stopped.
+    pub fn pass(&self, controller: &mut Controller) -> bool {
+        if controller.is_running_program() {
+            controller.update();
+            while let Ok((_, reply)) = self.jobs.try_recv() {
+                let _ = reply.send(Err("Busy running a program"));
+            }
+            return true;
+        }
+        match controller.idle_while(|| self.jobs.recv_timeout(STATUS_PERIOD)) {
+            Ok((job, reply)) => {
+                let _ = reply.send(self.run(job, controller));
+                controller.save_state();
+                controller.publish_status();
+                true
+            }
+            Err(RecvTimeoutError::Timeout) => true,
+            Err(RecvTimeoutError::Disconnected) => false,
+        }
+    }
+
+    fn run(&self, job: Job, controller: &mut Controller) -> Result<(), &'static str> {
+        match job {
+            Job::LoadProgram(path) => controller.load_program(&path),
+            Job::SetPosition(mm) => controller.set_current_position(mm),
+            Job::SetPaper(paper) => {
+                let [x_limit, y_limit] = paper.axis_limits();
+                controller.set_paper_limits(x_limit, y_limit);
+                Ok(())
+            }
+            Job::Fit(fit) => controller.fit_program(&fit),
+            Job::Home => controller.home(),
+            Job::Start(from) => {
+                if !controller.position_known() {
+                    return Err("Position unknown, set or home first");
+                }
+                controller.start_program(&from, &false)
+            }
+        }
+    }
+}
+
+fn header(field: &str, value: &str) -> Header {
+    Header::from_bytes(field.as_bytes(), value.as_bytes()).unwrap()
+}
+
+/// Hand a job to the controller thread and wait for it to be done
+fn submit(jobs: &Sender<(Job, Reply)>, job: Job) -> Result<(), &'static str> {
+    let (reply, result) = mpsc::channel();
+    jobs.send((job, reply)).map_err(|_| "Controller stopped")?;
+    result.recv().map_err(|_| "Controller stopped")?
+}
+
+fn send_control(
+    control: &Sender<ControlCommand>,
+    command: ControlCommand,
+) -> Result<(), &'static str> {
+    control.send(command).map_err(|_| "Controller stopped")
+}
+
+fn handle(
+    mut request: Request,
+    jobs: &Sender<(Job, Reply)>,
+    control: &Sender<ControlCommand>,
+    status: Arc<Mutex<Status>>,
+    upload_dir: &Path,
+) {
+    let url = request.url().to_owned();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let response = match (request.method(), path) {
+        (Method::Get, "/") => {
+            Response::from_string(PAGE).with_header(header("Content-Type", "text/html"))
+        }
+        (Method::Get, "/status") => {
+            let json = serde_json::to_string(&*status.lock().unwrap()).unwrap();
+            Response::from_string(json).with_header(header("Content-Type", "application/json"))
+        }
+        (Method::Get, "/ws") => {
+            stream_status(request, status);
+            return;
+        }
+        (Method::Post, _) => {
+            let mut body = String::new();
+            let result = match request.as_reader().read_to_string(&mut body) {
+                Ok(_) => post(path, query, body.trim(), jobs, control, upload_dir),
+                Err(_) => Err("Failed to read request body"),
+            };
+            match result {
+                Ok(()) => Response::from_string("ok"),
+                Err(msg) => Response::from_string(msg).with_status_code(StatusCode(400)),
+            }
+        }
+        _ => Response::from_string("Not found").with_status_code(StatusCode(404)),
+    };
+    if let Err(e) = request.respond(response) {
+        error!("{e}");
+    }
+}
+
+/// Undo the %XX escapes and + for space of a query string value, keeping any malformed escape
+fn percent_decode(value: &str) -> String {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        let escaped = tail
+            .get(..2)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (b, escaped) {
+            (b'%', Some(decoded)) => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+                continue;
+            }
+            (b'+', _) => bytes.push(b' '),
+            _ => bytes.push(b),
+        }
+        rest = tail;
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+fn post(
+    path: &str,
+    query: &str,
+    body: &str,
+    jobs: &Sender<(Job, Reply)>,
+    control: &Sender<ControlCommand>,
+    upload_dir: &Path,
+) -> Result<(), &'static str> {
+    match path {
+        "/program" => {
+            let name = query
+                .split('&')
+                .find_map(|pair| pair.strip_prefix("name="))
+                .map(percent_decode)
+                .unwrap_or_default();
+            // keep only the file name so uploads can not be written outside the directory
+            let name = Path::new(&name)
+                .file_name()
+                .unwrap_or("upload.gcode".as_ref());
+            let file = upload_dir.join(name);
+            fs::write(&file, body).map_err(|e| {
+                error!("{}: {e}", file.display());
+                "Failed to save upload"
+            })?;
+            submit(jobs, Job::LoadProgram(file))
+        }
+        "/position" => submit(jobs, Job::SetPosition(body.parse()?)),
+        "/paper" => submit(jobs, Job::SetPaper(body.parse()?)),
+        "/fit" => {
+            let fit = Fit::from_str(body, true).map_err(|_| "Unknown fit")?;
+            submit(jobs, Job::Fit(fit))
+        }
+        "/home" => submit(jobs, Job::Home),
+        "/start" => {
+            let from = if body.is_empty() {
+                0
+            } else {
+                body.parse().map_err(|_| "Failed to parse")?
+            };
+            submit(jobs, Job::Start(from))
+        }
+        "/pause" => send_control(control, ControlCommand::Pause { lift_pen: true }),
+        "/resume" => send_control(control, ControlCommand::Resume),
+        "/abort" => send_control(control, ControlCommand::Abort),
+        _ => Err("Not found"),
+    }
+}
+
+/// Upgrade to a websocket and send the status as json until the client goes away
+fn stream_status(request: Request, status: Arc<Mutex<Status>>) {
+    let key = request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Sec-WebSocket-Key"))
+        .map(|h| h.value.to_string());
+    let Some(key) = key else {
+        let response = Response::from_string("Expected a websocket").with_status_code(400);
+        let _ = request.respond(response);
+        return;
+    };
+    let response = Response::empty(StatusCode(101)).with_header(header(
+        "Sec-WebSocket-Accept",
+        &derive_accept_key(key.as_bytes()),
+    ));
+    let stream = request.upgrade("websocket", response);
+    let mut socket = WebSocket::from_raw_socket(stream, Role::Server, None);
+    loop {
+        let json = serde_json::to_string(&*status.lock().unwrap()).unwrap();
+        if socket.send(Message::Text(json)).is_err() {
+            break;
+        }
+        thread::sleep(STATUS_PERIOD);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upload_names_are_percent_decoded() {
+        assert_eq!(percent_decode("my%20plot.svg"), "my plot.svg");
+        assert_eq!(percent_decode("a+b%2Fc%C3%A9"), "a b/cé");
+        assert_eq!(percent_decode("100%.gcode"), "100%.gcode");
+    }
+}
@@ -0,0 +1,112 @@
+use std::{path::Path, sync::Arc};
+
+use log::info;
+
+use crate::{
+    clock::{Clock, VirtualClock},
+    controller::{format_time, Controller},
+    gcode::{PlotterInstruction, PlotterProgram},
+    motor::{DriverKind, SimulatedHardware},
+    physical::Physical,
+    position::{Position, PositionMM, PositionStep},
+    render::SvgCanvas,
+};
+
+/// Consecutive positions drawn with the pen in one state
+struct Stroke {
+    pen_down: bool,
+    points: Vec<PositionMM>,
+}
+
+/// Run a program through the controller against recording motors and a virtual clock, then
+/// render the path the steps actually produced to an svg.
+///
+/// tick: seconds of simulated time per pass of the motion loop
+pub fn simulate(
+    physical: Physical,
+    mut program: PlotterProgram,
+    start: PositionMM,
+    tick: f64,
+    output: &Path,
+) -> Result<(), &'static str> {
+    let hardware = SimulatedHardware::new(&start, &physical);
+    let journal = hardware.journal();
+    let motors = Controller::make_motors(&physical, DriverKind::Recording, &hardware);
+    let clock = Arc::new(VirtualClock::new(tick));
+    let pen_lift = DriverKind::Recording.build_pen_lift(&physical);
+    let mut controller =
+        Controller::with_hardware(physical.clone(), motors, pen_lift, clock.clone(), None);
+    controller.set_current_position(start)?;
+
+    let mut step: PositionStep = *Position::from_mm(start, &physical).get_step();
+    let mut strokes = vec![Stroke {
+        pen_down: false,
+        points: vec![Position::from_step(step, &physical).into()],
+    }];
+    let len = program.len();
+    while let Some(instruction) = program.next() {
+        let i = program.current_position();
+        if let PlotterInstruction::PenUp | PlotterInstruction::PenDown = instruction {
+            let last = *strokes.last().unwrap().points.last().unwrap();
+            strokes.push(Stroke {
+                pen_down: matches!(instruction, PlotterInstruction::PenDown),
+                points: vec![last],
+            });
+        }
+        if let PlotterInstruction::ChangePen(pen) = instruction {
+            // nobody is there to change it
+            info!("change to {pen} at instruction {i}");
+            continue;
+        }
+        let upcoming = program.upcoming_moves(controller.look_ahead());
+        controller.run_instruction(&instruction, &upcoming)?;
+        // replay the recorded steps to find where the pen really went
+        let stroke = strokes.last_mut().unwrap();
+        for (side, rotation) in journal.lock().unwrap().drain(..) {
+            step.step(side.index(), &side.step_instruction(&rotation));
+            stroke
+                .points
+                .push(Position::from_step(step, &physical).into());
+        }
+        if i.is_multiple_of(1000) {
+            info!("simulated {i}/{len}, t: {}", format_time(clock.now()));
+        }
+    }
+    let total_time = clock.now();
+    info!(
+        "simulated time: {}, bad_prevented: {}",
+        format_time(total_time),
+        controller.bad_steps_prevented()
+    );
+
+    let x_limits = [
+        *physical.get_motor_position(0).x(),
+        *physical.get_motor_position(1).x(),
+    ];
+    let y_limits = [0.0, *physical.get_motor_position(0).y()];
+    let mut canvas = SvgCanvas::new(x_limits, y_limits);
+    canvas.rect(
+        physical.get_x_limits(),
+        &physical.get_y_limits(),
+        "stroke:#c0c0c0;stroke-width:0.3",
+    );
+    for stroke in &strokes {
+        let style = if stroke.pen_down {
+            "stroke:#000000;stroke-width:0.3"
+        } else {
+            "stroke:#6495ed;stroke-width:0.2;stroke-dasharray:1,1"
+        };
+        canvas.polyline(&stroke.points, style);
+    }
+    canvas.text(
+        &PositionMM::new([x_limits[0] + 5.0, y_limits[0] + 5.0]),
+        &format!(
+            "simulated time: {}, bad steps prevented: {}",
+            format_time(total_time),
+            controller.bad_steps_prevented()
+        ),
+    );
+    canvas.save(output)?;
+    info!("wrote {}", output.display());
+    Ok(())
+}
@@ -0,0 +1,132 @@
+use std::{
+    fmt::Display,
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use log::error;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::gcode::ProgramTransform;
+
+/// What the controller knows about the machine and the job, kept across restarts
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionState {
+    /// Cord lengths in steps, if the position is known
+    step: Option<[i64; 2]>,
+    /// Paper x and y limits in mm
+    paper_limits: Option<[[f64; 2]; 2]>,
+    /// gcode file the loaded program was read from
+    program_path: Option<PathBuf>,
+    /// Scaling and centering applied to the program after it was read
+    transforms: Vec<ProgramTransform>,
+    /// Number of program instructions that finished
+    completed: usize,
+}
+
+impl SessionState {
+    pub fn new(
+        step: Option<[i64; 2]>,
+        paper_limits: Option<[[f64; 2]; 2]>,
+        program_path: Option<PathBuf>,
+        transforms: Vec<ProgramTransform>,
+        completed: usize,
+    ) -> Self {
+        SessionState {
+            step,
+            paper_limits,
+            program_path,
+            transforms,
+            completed,
+        }
+    }
+    /// Read a state file, which is not an error if there is none yet
+    pub fn load(path: &Path) -> Result<Option<Self>, &'static str> {
+        load_toml(path)
+    }
+    pub fn save(&self, path: &Path) -> Result<(), &'static str> {
+        save_toml(self, path)
+    }
+    pub fn get_step(&self) -> &Option<[i64; 2]> {
+        &self.step
+    }
+    pub fn get_paper_limits(&self) -> &Option<[[f64; 2]; 2]> {
+        &self.paper_limits
+    }
+    pub fn get_program_path(&self) -> &Option<PathBuf> {
+        &self.program_path
+    }
+    pub fn get_transforms(&self) -> &[ProgramTransform] {
+        &self.transforms
+    }
+    pub fn get_completed(&self) -> &usize {
+        &self.completed
+    }
+}
+
+impl Display for SessionState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.step {
+            Some(rr) => write!(f, "step: [{}, {}]", rr[0], rr[1])?,
+            None => write!(f, "step: unknown")?,
+        }
+        if let Some([x, y]) = self.paper_limits {
+            write!(f, ", paper: x [{}, {}] y [{}, {}]", x[0], x[1], y[0], y[1])?;
+        }
+        if let Some(path) = &self.program_path {
+            write!(
+                f,
+                ", program: {} ({} transforms, {} instructions done)",
+                path.display(),
+                self.transforms.len(),
+                self.completed
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Read a toml file kept by eveline, which is not an error if there is none yet
+pub fn load_toml<T: DeserializeOwned>(path: &Path) -> Result<Option<T>, &'static str> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let text = fs::read_to_string(path).map_err(|e| {
+        error!("{}: {e}", path.display());
+        "Failed to read toml file"
+    })?;
+    let value = toml::from_str(&text).map_err(|e| {
+        error!("{}: {e}", path.display());
+        "Failed to parse toml file"
+    })?;
+    Ok(Some(value))
+}
+
+/// Write a toml file, replacing the old one only once the new one is complete, so a power
+/// cut part way through leaves the old one in place
+pub fn save_toml<T: Serialize>(value: &T, path: &Path) -> Result<(), &'static str> {
+    let text = toml::to_string(value).map_err(|e| {
+        error!("{e}");
+        "Failed to serialize toml"
+    })?;
+    let partial = path.with_extension("partial");
+    // the new file has to be on disk before it replaces the old one, and the rename has to be
+    // on disk before we carry on
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::create(&partial)
+        .and_then(|mut file| {
+            file.write_all(text.as_bytes())?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&partial, path))
+        .and_then(|_| File::open(dir)?.sync_all())
+        .map_err(|e| {
+            error!("{}: {e}", path.display());
+            "Failed to write toml file"
+        })
+}
@@ -0,0 +1,133 @@
+use std::{fs, path::Path};
+
+use log::{error, warn};
+use nalgebra::Vector2;
+use usvg::{
+    tiny_skia_path::{PathSegment, Point},
+    Group, Node, Options, Tree,
+};
+
+use crate::{
+    gcode::{Feed, PlotterInstruction},
+    position::PositionMM,
+};
+
+/// svg user units are css pixels, 96 to the inch
+const MM_PER_PX: f64 = 25.4 / 96.0;
+
+/// Outline of every visible path in an svg file, in mm with y up and the bottom left corner
+/// of the page at the origin
+///
+/// Shapes, transforms and the viewBox are resolved by usvg. Curves are flattened to chords
+/// no further than `tolerance` mm from them.
+pub fn read_svg(path: &Path, tolerance: &f64) -> Result<Vec<PlotterInstruction>, &'static str> {
+    let data = fs::read(path).map_err(|e| {
+        error!("{}: {e}", path.display());
+        "Failed to read svg file"
+    })?;
+    let tree = Tree::from_data(&data, &Options::default()).map_err(|e| {
+        error!("{}: {e}", path.display());
+        "Failed to parse svg file"
+    })?;
+    let mut flattener = Flattener {
+        height: tree.size().height() as f64 * MM_PER_PX,
+        tolerance: *tolerance,
+        instructions: Vec::new(),
+        last: Vector2::zeros(),
+    };
+    flattener.group(tree.root());
+    if flattener.instructions.is_empty() {
+        return Err("No paths in svg file");
+    }
+    flattener.instructions.push(PlotterInstruction::PenUp);
+    Ok(flattener.instructions)
+}
+
+struct Flattener {
+    /// Page height in mm, to turn y down into y up
+    height: f64,
+    tolerance: f64,
+    instructions: Vec<PlotterInstruction>,
+    /// End of the last segment in mm
+    last: Vector2<f64>,
+}
+
+impl Flattener {
+    fn group(&mut self, group: &Group) {
+        for node in group.children() {
+            match node {
+                Node::Group(group) => self.group(group),
+                Node::Path(path) if path.is_visible() => {
+                    match path.data().clone().transform(path.abs_transform()) {
+                        Some(data) => self.path(&data),
+                        None => warn!("skipping path {} with a degenerate transform", path.id()),
+                    }
+                }
+                Node::Path(_) => {}
+                _ => warn!("skipping svg element {} that is not a path", node.id()),
+            }
+        }
+    }
+    fn path(&mut self, data: &usvg::tiny_skia_path::Path) {
+        let mut start = self.last;
+        for segment in data.segments() {
+            match segment {
+                PathSegment::MoveTo(p) => {
+                    start = self.mm(p);
+                    self.travel(start);
+                }
+                PathSegment::LineTo(p) => self.draw(self.mm(p)),
+                PathSegment::QuadTo(p1, p2) => {
+                    let [p0, p1, p2] = [self.last, self.mm(p1), self.mm(p2)];
+                    let bend = (p0 - 2.0 * p1 + p2).norm();
+                    self.curve(bend / 4.0, |t| {
+                        let s = 1.0 - t;
+                        s * s * p0 + 2.0 * s * t * p1 + t * t * p2
+                    });
+                }
+                PathSegment::CubicTo(p1, p2, p3) => {
+                    let [p0, p1, p2, p3] = [self.last, self.mm(p1), self.mm(p2), self.mm(p3)];
+                    let bend = (p0 - 2.0 * p1 + p2).norm().max((p1 - 2.0 * p2 + p3).norm());
+                    self.curve(bend * 0.75, |t| {
+                        let s = 1.0 - t;
+                        s * s * s * p0
+                            + 3.0 * s * s * t * p1
+                            + 3.0 * s * t * t * p2
+                            + t * t * t * p3
+                    });
+                }
+                PathSegment::Close => self.draw(start),
+            }
+        }
+    }
+    fn mm(&self, p: Point) -> Vector2<f64> {
+        Vector2::new(p.x as f64 * MM_PER_PX, self.height - p.y as f64 * MM_PER_PX)
+    }
+    fn travel(&mut self, target: Vector2<f64>) {
+        self.instructions.extend([
+            PlotterInstruction::PenUp,
+            PlotterInstruction::Move {
+                target: PositionMM::new([target.x, target.y]),
+                feed: Feed::Rapid,
+            },
+            PlotterInstruction::PenDown,
+        ]);
+        self.last = target;
+    }
+    fn draw(&mut self, target: Vector2<f64>) {
+        self.instructions.push(PlotterInstruction::Move {
+            target: PositionMM::new([target.x, target.y]),
+            feed: Feed::Max,
+        });
+        self.last = target;
+    }
+    /// Chords along a curve from t = 0 to 1
+    ///
+    /// error: chord error of a single chord, which falls with the square of the chord count
+    fn curve(&mut self, error: f64, at: impl Fn(f64) -> Vector2<f64>) {
+        let n = (error / self.tolerance).sqrt().ceil().max(1.0) as usize;
+        for i in 1..=n {
+            self.draw(at(i as f64 / n as f64));
+        }
+    }
+}